@@ -0,0 +1,408 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use serde::de::{Error, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const DEFAULT_DECIMALS: u32 = 4;
+// 10^18 units still leave 20 digits for the integer part.
+const MAX_DECIMALS: u32 = 18;
+
+// The number of decimals of all amounts. Amounts are parsed and formatted (e.g. by serde) without access to the
+// configuration of a run, so the decimals are fixed per process: set once, before any amount is parsed.
+static DECIMALS: OnceLock<u32> = OnceLock::new();
+
+// Set `lock` to `value`, unless it was set to another value already.
+fn set_once<T: PartialEq + Copy + fmt::Debug>(
+    lock: &OnceLock<T>,
+    value: T,
+    name: &str,
+) -> Result<(), String> {
+    let set = *lock.get_or_init(|| value);
+    if set != value {
+        return Err(format!(
+            "Amounts use the {} {:?} in this process, {:?} can't be used.",
+            name, set, value
+        ));
+    }
+    Ok(())
+}
+
+// Set the number of decimals of all amounts. Defaults to 4 if it is never set.
+// The decimals are fixed per process: the first call (or run, see `Config::decimals`) sets them, later calls and
+// runs asking for other decimals fail.
+pub fn set_decimals(decimals: u32) -> Result<(), String> {
+    if decimals > MAX_DECIMALS {
+        return Err(format!(
+            "At most {} decimals are supported, got {}.",
+            MAX_DECIMALS, decimals
+        ));
+    }
+    set_once(&DECIMALS, decimals, "decimals")
+}
+
+pub fn decimals() -> u32 {
+    DECIMALS.get().copied().unwrap_or(DEFAULT_DECIMALS)
+}
+
+// The number of units in 1.0.
+pub fn scale() -> i128 {
+    10i128.pow(decimals())
+}
+
+// A fixed-point amount, stored as a number of units of the smallest decimal.
+// With the default of 4 decimals, 1.5 is stored as 15000.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Amount(i128);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_units(units: i128) -> Amount {
+        Amount(units)
+    }
+
+    pub fn units(self) -> i128 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    // `percentage` percent of the amount, or `None` if it overflows. The percentage has the same decimals as
+    // amounts. Fractions below the smallest unit are truncated.
+    pub fn percentage(self, percentage: Amount) -> Option<Amount> {
+        self.0
+            .checked_mul(percentage.0)
+            .map(|units| Amount(units / (100 * scale())))
+    }
+}
+
+// What happens to the digits of an amount beyond the configured decimals.
+// Trailing zeros are always accepted, they don't change the amount.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Rounding {
+    // The amount is rejected.
+    #[default]
+    Reject,
+    // The digits are dropped (rounding towards zero).
+    Truncate,
+    // The amount is rounded to the nearest unit, ties to the even unit.
+    HalfEven,
+}
+
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Rounding::Reject),
+            "truncate" => Ok(Rounding::Truncate),
+            "half-even" => Ok(Rounding::HalfEven),
+            _ => Err(format!("Unknown rounding '{}'.", s)),
+        }
+    }
+}
+
+// The rounding of all parsed amounts, fixed per process like `DECIMALS`.
+static ROUNDING: OnceLock<Rounding> = OnceLock::new();
+
+// Set the rounding of all parsed amounts. Defaults to `Rounding::Reject` if it is never set.
+// Like the decimals, the rounding is fixed per process, see `set_decimals`.
+pub fn set_rounding(rounding: Rounding) -> Result<(), String> {
+    set_once(&ROUNDING, rounding, "rounding")
+}
+
+// Use the decimals (4 if `None`) and rounding of a run. Fails if the process already uses others.
+pub fn configure(decimals: Option<u32>, rounding: Rounding) -> Result<(), String> {
+    set_decimals(decimals.unwrap_or(DEFAULT_DECIMALS))?;
+    set_rounding(rounding)
+}
+
+fn rounding() -> Rounding {
+    ROUNDING.get().copied().unwrap_or_default()
+}
+
+fn parse_digits(s: &str) -> Option<i128> {
+    if s.is_empty() || !s.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+// Whether the digits dropped by rounding round the kept digits up.
+fn rounds_up(kept: i128, dropped: &str, rounding: Rounding) -> Result<bool, String> {
+    if dropped.bytes().all(|byte| byte == b'0') {
+        return Ok(false);
+    }
+    match rounding {
+        Rounding::Reject => Err("Too many decimals.".to_string()),
+        Rounding::Truncate => Ok(false),
+        Rounding::HalfEven => {
+            let (first, rest) = dropped.split_at(1);
+            Ok(match first {
+                "5" if rest.bytes().all(|byte| byte == b'0') => kept % 2 == 1,
+                _ => first >= "5",
+            })
+        }
+    }
+}
+
+fn parse(s: &str, decimals: u32, rounding: Rounding) -> Result<Amount, String> {
+    // Operations without an amount (e.g. `unlock`) may leave the column empty.
+    if s.is_empty() {
+        return Ok(Amount::ZERO);
+    }
+
+    let (negative, unsigned) = match s.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, s),
+    };
+    let (integer, fractional) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    let decimals = decimals as usize;
+    let (kept, dropped) = fractional.split_at(fractional.len().min(decimals));
+
+    let integer_part = parse_digits(integer).ok_or("Failed to parse integer part.")?;
+    let fractional_part = if kept.is_empty() {
+        0
+    } else {
+        parse_digits(&format!("{:0<width$}", kept, width = decimals))
+            .ok_or("Failed to parse fractional part.")?
+    };
+    if !dropped.is_empty() && parse_digits(dropped).is_none() {
+        return Err("Failed to parse fractional part.".to_string());
+    }
+
+    let mut units = integer_part
+        .checked_mul(10i128.pow(decimals as u32))
+        .and_then(|units| units.checked_add(fractional_part))
+        .ok_or("The amount is too large.")?;
+    if rounds_up(units, dropped, rounding)? {
+        units = units.checked_add(1).ok_or("The amount is too large.")?;
+    }
+    Ok(Amount(if negative { -units } else { units }))
+}
+
+// Parses an amount with up to the configured number of decimals, with an optional `-` sign.
+// Digits beyond the decimals are handled according to the configured rounding.
+impl FromStr for Amount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s, decimals(), rounding())
+    }
+}
+
+fn format(amount: Amount, decimals: u32, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let scale = 10u128.pow(decimals);
+    let sign = if amount.0 < 0 { "-" } else { "" };
+    let units = amount.0.unsigned_abs();
+    let (integer_part, fractional_part) = (units / scale, units % scale);
+    // Whole amounts are written with a single decimal, e.g. `1.0`.
+    if fractional_part == 0 {
+        write!(f, "{}{}.0", sign, integer_part)
+    } else {
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            integer_part,
+            fractional_part,
+            width = decimals as usize
+        )
+    }
+}
+
+// Writes all decimals, e.g. `1.0500` and `-0.5000` with 4 decimals, except for whole amounts (`1.0`).
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        format(*self, decimals(), f)
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+struct AmountVisitor;
+
+// Amounts read from the input, credit limits and fee schedules are never negative.
+impl Visitor<'_> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a non-negative decimal amount")
+    }
+
+    fn visit_str<E: Error>(self, s: &str) -> Result<Amount, E> {
+        let amount: Amount = s.parse().map_err(E::custom)?;
+        if amount < Amount::ZERO {
+            return Err(E::custom(format!("Negative amount '{}'.", s)));
+        }
+        Ok(amount)
+    }
+}
+
+// Visiting the text works with borrowed strings (csv) as well as with strings of a streamed snapshot.
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(AmountVisitor)
+    }
+}
+
+// Serializes the units instead of the formatted amount. Unlike the formatted amount, negative units can be read
+// back. Use with `#[serde(with = "crate::amount::units")]`.
+pub mod units {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::Amount;
+
+    pub fn serialize<S: Serializer>(amount: &Amount, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i128(amount.0)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        i128::deserialize(deserializer).map(Amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    // Formats with the given decimals instead of the configured ones.
+    struct WithDecimals(Amount, u32);
+
+    impl fmt::Display for WithDecimals {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            format(self.0, self.1, f)
+        }
+    }
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!("1.5".parse(), Ok(Amount(15000)));
+        assert_eq!("1.".parse(), Ok(Amount(10000)));
+        assert_eq!("".parse(), Ok(Amount::ZERO));
+        assert_eq!("-0.5".parse(), Ok(Amount(-5000)));
+        // Integer parts beyond u32
+        assert_eq!("4294967296.0001".parse(), Ok(Amount(42949672960001)));
+        // Trailing zeros don't need rounding.
+        assert_eq!("1.500000".parse(), Ok(Amount(15000)));
+        assert!("1.00001".parse::<Amount>().is_err());
+        assert!(".5".parse::<Amount>().is_err());
+        assert!("1.+5".parse::<Amount>().is_err());
+        assert!("1.5x".parse::<Amount>().is_err());
+        assert!("--1".parse::<Amount>().is_err());
+    }
+
+    #[test]
+    fn test_parse_with_other_decimals() {
+        let reject = |s| parse(s, 8, Rounding::Reject);
+        assert_eq!(
+            parse("1.000001", 6, Rounding::Reject),
+            Ok(Amount(1_000_001))
+        );
+        assert_eq!(
+            reject("123456789012.5"),
+            Ok(Amount(12_345_678_901_250_000_000))
+        );
+        assert_eq!(parse("7", 0, Rounding::Reject), Ok(Amount(7)));
+        assert_eq!(parse("7.0", 0, Rounding::Reject), Ok(Amount(7)));
+        assert!(parse("1.5", 0, Rounding::Reject).is_err());
+        assert!(parse("999999999999999999999", 18, Rounding::Reject).is_err());
+    }
+
+    #[test]
+    fn test_rounding() {
+        let truncate = |s| parse(s, 2, Rounding::Truncate);
+        assert_eq!(truncate("1.239"), Ok(Amount(123)));
+        assert_eq!(truncate("-1.239"), Ok(Amount(-123)));
+        assert!(truncate("1.23x").is_err());
+
+        let half_even = |s| parse(s, 2, Rounding::HalfEven);
+        assert_eq!(half_even("1.234"), Ok(Amount(123)));
+        assert_eq!(half_even("1.236"), Ok(Amount(124)));
+        // Ties round to the even unit.
+        assert_eq!(half_even("1.235"), Ok(Amount(124)));
+        assert_eq!(half_even("1.245"), Ok(Amount(124)));
+        assert_eq!(half_even("1.2450"), Ok(Amount(124)));
+        assert_eq!(half_even("1.2451"), Ok(Amount(125)));
+        assert_eq!(half_even("-1.235"), Ok(Amount(-124)));
+        assert_eq!(half_even("0.995"), Ok(Amount(100)));
+
+        assert!(parse("1.235", 2, Rounding::Reject).is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let display = |units, decimals| WithDecimals(Amount(units), decimals).to_string();
+        assert_eq!(display(10500, 4), "1.0500");
+        assert_eq!(display(-5000, 4), "-0.5000");
+        assert_eq!(display(-13333, 4), "-1.3333");
+        assert_eq!(display(10000, 4), "1.0");
+        assert_eq!(display(0, 4), "0.0");
+        assert_eq!(display(7, 0), "7.0");
+        assert_eq!(display(1, 8), "0.00000001");
+        assert_eq!(Amount(15000).to_string(), "1.5000");
+    }
+
+    // Formatting and parsing again gives the same amount, for any number of decimals and rounding.
+    #[test]
+    fn test_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10000 {
+            let decimals = rng.gen_range(0..=MAX_DECIMALS);
+            let amount = Amount(match rng.gen_range(0..3) {
+                0 => rng.gen_range(-1000..1000),
+                1 => rng.gen_range(-(1 << 64)..(1 << 64)),
+                // i128::MIN has no positive counterpart to parse.
+                _ => rng.gen_range(-i128::MAX..=i128::MAX),
+            });
+            let rounding =
+                [Rounding::Reject, Rounding::Truncate, Rounding::HalfEven][rng.gen_range(0..3)];
+            let s = WithDecimals(amount, decimals).to_string();
+            assert_eq!(parse(&s, decimals, rounding), Ok(amount), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_deserialize_rejects_negative_amounts() {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader("1.5\n-1.5".as_bytes());
+        let amounts: Vec<Result<Amount, csv::Error>> = reader.deserialize().collect();
+        assert_eq!(*amounts[0].as_ref().unwrap(), Amount(15000));
+        assert!(amounts[1].is_err());
+    }
+
+    #[test]
+    fn test_settings_are_fixed_per_process() {
+        let lock = OnceLock::new();
+        assert_eq!(set_once(&lock, Rounding::Truncate, "rounding"), Ok(()));
+        assert_eq!(set_once(&lock, Rounding::Truncate, "rounding"), Ok(()));
+        assert!(set_once(&lock, Rounding::HalfEven, "rounding").is_err());
+        assert_eq!(lock.get(), Some(&Rounding::Truncate));
+        assert!(set_decimals(MAX_DECIMALS + 1).is_err());
+    }
+
+    #[test]
+    fn test_percentage() {
+        // 1.5% of 1.0
+        assert_eq!(Amount(10000).percentage(Amount(15000)), Some(Amount(150)));
+        // 1.5% of 0.0001
+        assert_eq!(Amount(1).percentage(Amount(15000)), Some(Amount(0)));
+        assert_eq!(Amount(i128::MAX).percentage(Amount(20000)), None);
+    }
+}
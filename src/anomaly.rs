@@ -0,0 +1,37 @@
+use serde::Serialize;
+
+use crate::operation::{Operation, OperationType};
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub enum AnomalyKind {
+    // An operation referencing a transaction (e.g. a dispute) references a transaction of another client.
+    CrossClientReference,
+    // A deposit or withdrawal reuses a transaction id (only reported if duplicates are flagged).
+    DuplicateTx,
+}
+
+// One line of the anomaly report. Anomalies usually point to corrupted input data or fraud attempts.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Anomaly {
+    pub line: u64,
+    pub kind: AnomalyKind,
+    #[serde(rename = "type")]
+    pub type_: OperationType,
+    pub client: u16,
+    pub tx: u32,
+    // The client that owns the transaction id.
+    pub owner: u16,
+}
+
+impl Anomaly {
+    pub fn new(line: u64, kind: AnomalyKind, operation: &Operation, owner: u16) -> Self {
+        Anomaly {
+            line,
+            kind,
+            type_: operation.type_,
+            client: operation.client,
+            tx: operation.tx_id,
+            owner,
+        }
+    }
+}
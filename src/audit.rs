@@ -0,0 +1,55 @@
+use serde::Serialize;
+
+use crate::client_state::{Rejection, Transition};
+use crate::operation::{Operation, OperationType};
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub enum Outcome {
+    #[serde(rename = "applied")]
+    Applied,
+    #[serde(rename = "rejected")]
+    Rejected,
+}
+
+// One line of the audit log: what happened to the operation in line `line` of the input. The operation is only
+// missing if the line could not be parsed.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct AuditRecord {
+    pub line: u64,
+    #[serde(rename = "type")]
+    pub type_: Option<OperationType>,
+    pub client: Option<u16>,
+    pub tx: Option<u32>,
+    pub outcome: Outcome,
+    pub reason: Option<Rejection>,
+}
+
+impl AuditRecord {
+    pub fn new(line: u64, operation: &Operation, result: &Result<Transition, Rejection>) -> Self {
+        let (outcome, reason) = match result {
+            Ok(_) => (Outcome::Applied, None),
+            Err(rejection) => (Outcome::Rejected, Some(*rejection)),
+        };
+
+        AuditRecord {
+            line,
+            type_: Some(operation.type_),
+            client: Some(operation.client),
+            tx: Some(operation.tx_id),
+            outcome,
+            reason,
+        }
+    }
+
+    // A line of the input that is not a valid operation.
+    pub fn unparseable(line: u64) -> Self {
+        AuditRecord {
+            line,
+            type_: None,
+            client: None,
+            tx: None,
+            outcome: Outcome::Rejected,
+            reason: Some(Rejection::Unparseable),
+        }
+    }
+}
@@ -1,132 +1,986 @@
-use std::collections::HashMap;
+use std::collections::{HashSet, VecDeque};
+use std::{fmt, io};
 
-use serde::Serialize;
+use serde::de::{DeserializeSeed, Error, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, SerializeSeq};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::amount::Amount;
+use crate::config::{Config, DisputePolicy, RetentionAge};
+use crate::currency::Currency;
+use crate::event::{Event, EventKind};
+use crate::fee::{Fee, Fees};
 use crate::operation::{Operation, OperationType};
-use crate::serialize_fractional::serialize_fractional;
+use crate::rule::{Activity, RuleAction, RuleEngine, RuleMatch};
+use crate::timestamp::Timestamp;
+use crate::transaction::{Transaction, TransactionStatus};
+use crate::transaction_store::AnyStore;
 
-#[derive(Debug, PartialEq, Clone)]
-enum TransactionStatus {
-    Normal,
+// The change an operation applied to the client state.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub enum Transition {
+    Deposited,
+    Withdrawn,
     Disputed,
+    Resolved,
+    ChargedBack,
+    Unlocked,
+    Closed,
+    TransferredOut,
+    TransferredIn,
+    Authorized,
+    Captured,
+    Voided,
+    Refunded,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+// The reason an operation was not applied to the client state.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub enum Rejection {
+    AccountFrozen,
+    AccountClosed,
+    InDebt,
+    NotFrozen,
+    PendingDisputes,
+    InsufficientFunds,
+    UnknownTx,
+    AlreadyDisputed,
+    NotDisputed,
+    AlreadyChargedBack,
+    ExceedsRemainder,
+    ExceedsDisputed,
+    DuplicateTx,
+    InvalidTransfer,
+    CounterpartyRejected,
+    PendingAuthorizations,
+    NotAuthorized,
+    ExceedsAuthorized,
+    NotDisputable,
+    NotRefundable,
+    DisputeWindowExpired,
+    BlockedByRule,
+    Expired,
+    Overflow,
+    // The stored transactions could not be read or written, e.g. because the disk is full.
+    StorageFailed,
+    // The line of the input is not a valid operation.
+    Unparseable,
+}
+
+// A chargeback freezes the account, `unlock` reinstates it. `close` is final.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 enum ClientStatus {
     Normal,
     Frozen,
-}
-
-#[derive(Debug, PartialEq, Clone)]
-struct Transaction {
-    operation: Operation,
-    status: TransactionStatus,
+    Closed,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct ClientState {
     client: u16,
-    available: i64,
-    held: i64,
+    // The funds per currency, in the order the currencies were first used. Most clients use only
+    // a few currencies, so a vector is faster than a map.
+    balances: Vec<Balance>,
     status: ClientStatus,
+    dispute_policy: DisputePolicy,
+    // Withdrawals may overdraw the available funds (of each currency) down to this amount, the negated
+    // credit limit.
+    min_available: Amount,
+    fees: Fees,
+    // Disputes later than this many seconds after the disputed transaction are rejected.
+    dispute_window: Option<u64>,
+    rules: RuleEngine,
+    activity: Activity,
+    // The rules matched by the latest operations, until the caller takes them.
+    rule_matches: Vec<RuleMatch>,
+    // Whether events are collected, see `EventKind`. The events of the latest operations are kept until the
+    // caller takes them.
+    collect_events: bool,
+    events: Vec<Event>,
+    // Transactions are evicted once there are more than `max_transactions` or they are older than `retain_for`.
+    max_transactions: Option<usize>,
+    retain_for: Option<u64>,
+
+    transactions: AnyStore,
+    // The number of stored transactions that are disputed or authorized, so closing the account doesn't have to
+    // read all transactions.
+    disputed: usize,
+    authorized: usize,
+    // The ids of the stored transactions, oldest first. Only kept if transactions are evicted.
+    tx_order: VecDeque<u32>,
+    // The ids of transactions taken out of `tx_order` because they were disputed or authorized when they were due
+    // for eviction. They go back to the front of `tx_order` once they are settled, see `commit`.
+    held_back: HashSet<u32>,
+    // The ids of evicted transactions. It grows by a few bytes per evicted transaction, so it is not bounded by
+    // the retention policy either.
+    expired: HashSet<u32>,
+}
+
+// The funds of a client in one currency.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+struct Balance {
+    currency: Currency,
+    // Negative funds are stored as units, see `amount::units`.
+    #[serde(with = "crate::amount::units")]
+    available: Amount,
+    #[serde(with = "crate::amount::units")]
+    held: Amount,
+    // Set once the available funds drop below the credit limit (e.g. by disputing a deposit that was already
+    // withdrawn). Blocks all withdrawals until the available funds are positive again.
+    in_debt: bool,
+    // The fees the client paid so far. They are already deducted from the available funds.
+    #[serde(with = "crate::amount::units")]
+    fees: Amount,
+}
+
+pub(crate) fn add(a: Amount, b: Amount) -> Result<Amount, Rejection> {
+    a.checked_add(b).ok_or(Rejection::Overflow)
+}
+
+pub(crate) fn sub(a: Amount, b: Amount) -> Result<Amount, Rejection> {
+    a.checked_sub(b).ok_or(Rejection::Overflow)
+}
+
+fn fee(fee: Fee, amount: Amount) -> Result<Amount, Rejection> {
+    fee.for_amount(amount).ok_or(Rejection::Overflow)
+}
+
+fn storage_failed(err: io::Error) -> Rejection {
+    eprintln!("Failed to access the stored transactions with {}", err);
+    Rejection::StorageFailed
+}
 
-    transactions: HashMap<u32, Transaction>,
+// The total funds (`available + held`) are written to the output, so they must not overflow either.
+fn check_total(balance: &Balance) -> Result<(), Rejection> {
+    add(balance.available, balance.held).map(|_| ())
+}
+
+// The account of an output row: a client, or the house account collecting the fees.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Account {
+    Client(u16),
+    House,
+}
+
+impl Serialize for Account {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Account::Client(client) => serializer.serialize_u16(*client),
+            Account::House => serializer.serialize_str("house"),
+        }
+    }
 }
 
 #[derive(Serialize)]
 pub struct ClientStateCsv {
-    client: u16,
-    #[serde(serialize_with = "serialize_fractional")]
-    available: i64,
-    #[serde(serialize_with = "serialize_fractional")]
-    held: i64,
-    #[serde(serialize_with = "serialize_fractional")]
-    total: i64,
+    client: Account,
+    available: Amount,
+    held: Amount,
+    total: Amount,
     locked: bool,
+    in_debt: bool,
+    currency: Currency,
 }
 
-impl From<ClientState> for ClientStateCsv {
-    fn from(state: ClientState) -> Self {
+impl ClientStateCsv {
+    // The row of the house account holding the fees collected in `currency`.
+    pub fn house(currency: Currency, fees: Amount) -> ClientStateCsv {
         ClientStateCsv {
-            client: state.client,
-            locked: state.status == ClientStatus::Frozen,
-            available: state.available,
-            held: state.held,
-            total: state.available + state.held,
+            client: Account::House,
+            available: fees,
+            held: Amount::ZERO,
+            total: fees,
+            locked: false,
+            in_debt: false,
+            currency,
         }
     }
 }
 
 impl ClientState {
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    // The available and held funds per currency.
+    pub fn funds(&self) -> impl Iterator<Item = (Currency, Amount, Amount)> + '_ {
+        self.balances
+            .iter()
+            .map(|balance| (balance.currency, balance.available, balance.held))
+    }
+
+    // The available and held funds in `currency`.
+    pub fn funds_in(&self, currency: Currency) -> (Amount, Amount) {
+        let balance = self.balance_in(currency);
+        (balance.available, balance.held)
+    }
+
+    // The fees paid by the client per currency.
+    pub fn fees_paid(&self) -> impl Iterator<Item = (Currency, Amount)> + '_ {
+        self.balances
+            .iter()
+            .map(|balance| (balance.currency, balance.fees))
+    }
+
+    // One row per currency of the client, sorted by currency. A client without any funds has one row
+    // for the default currency. Fails if the total funds of a currency overflow.
+    pub fn csv_rows(&self) -> Result<Vec<ClientStateCsv>, Rejection> {
+        let mut balances = self.balances.clone();
+        if balances.is_empty() {
+            balances.push(Balance::default());
+        }
+        balances.sort_by_key(|balance| balance.currency);
+
+        let locked = self.status != ClientStatus::Normal;
+        balances
+            .iter()
+            .map(|balance| {
+                Ok(ClientStateCsv {
+                    client: Account::Client(self.client),
+                    available: balance.available,
+                    held: balance.held,
+                    total: add(balance.available, balance.held)?,
+                    locked,
+                    in_debt: balance.in_debt,
+                    currency: balance.currency,
+                })
+            })
+            .collect()
+    }
+}
+
+// Writes the snapshot of a client state, see `ClientState::snapshot`. The state that is derived from the
+// configuration is not part of it. The client comes first, so a reader knows the client before its transactions.
+#[derive(Serialize)]
+pub struct ClientSnapshotRef<'a> {
+    client: u16,
+    balances: &'a [Balance],
+    status: &'a ClientStatus,
+    activity: &'a Activity,
+    transactions: StoredTransactions<'a>,
+    tx_order: &'a VecDeque<u32>,
+    held_back: &'a HashSet<u32>,
+    expired: &'a HashSet<u32>,
+}
+
+impl ClientSnapshotRef<'_> {
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+}
+
+// The stored transactions are read one by one while they are written. A transaction that can't be read fails
+// the snapshot.
+struct StoredTransactions<'a>(&'a AnyStore);
+
+impl Serialize for StoredTransactions<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for tx in self.0.transactions() {
+            seq.serialize_element(&tx.map_err(<S::Error as ser::Error>::custom)?)?;
+        }
+        seq.end()
+    }
+}
+
+impl ClientState {
+    #[cfg(test)]
     pub fn new(client: u16) -> ClientState {
+        ClientState::with_config(client, &Config::default())
+    }
+
+    #[cfg(test)]
+    pub fn with_config(client: u16, config: &Config) -> ClientState {
+        ClientState::with_store(client, config, AnyStore::default())
+    }
+
+    pub fn with_store(client: u16, config: &Config, store: AnyStore) -> ClientState {
         ClientState {
             client,
-            available: 0,
-            held: 0,
+            balances: Vec::new(),
             status: ClientStatus::Normal,
-            transactions: HashMap::new(),
+            dispute_policy: config.dispute_policy,
+            // Credit limits are never negative, so negating them can't overflow.
+            min_available: config
+                .credit_limits
+                .get(&client)
+                .and_then(|limit| Amount::ZERO.checked_sub(*limit))
+                .unwrap_or_default(),
+            fees: config.fee_schedule.fees_for(client),
+            dispute_window: config.dispute_window,
+            rules: config.rules.clone(),
+            activity: Activity::default(),
+            rule_matches: Vec::new(),
+            collect_events: config.event_sink.is_some() || config.journal_file.is_some(),
+            events: Vec::new(),
+            max_transactions: config.retention.max_transactions,
+            retain_for: match config.retention.max_age {
+                Some(RetentionAge::Seconds(seconds)) => Some(seconds),
+                Some(RetentionAge::DisputeWindow) => config.dispute_window,
+                None => None,
+            },
+            transactions: store,
+            disputed: 0,
+            authorized: 0,
+            tx_order: VecDeque::new(),
+            held_back: HashSet::new(),
+            expired: HashSet::new(),
         }
     }
+
+    // The client state to continue from in a later run. The configuration (e.g. fees and rules) is not part of
+    // the snapshot, it is taken from the later run.
+    pub fn snapshot(&self) -> ClientSnapshotRef<'_> {
+        ClientSnapshotRef {
+            client: self.client,
+            balances: &self.balances,
+            status: &self.status,
+            activity: &self.activity,
+            transactions: StoredTransactions(&self.transactions),
+            tx_order: &self.tx_order,
+            held_back: &self.held_back,
+            expired: &self.expired,
+        }
+    }
+}
+
+// The fields of a client snapshot, see `ClientSnapshotRef`.
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "snake_case")]
+enum ClientField {
+    Client,
+    Balances,
+    Status,
+    Activity,
+    Transactions,
+    TxOrder,
+    HeldBack,
+    Expired,
+    #[serde(other)]
+    Unknown,
+}
+
+// Continues from the snapshot of a client state written by an earlier run. The configuration (e.g. fees and rules)
+// is taken from the later run. The transactions are inserted into the store of the client while they are read, so
+// they are never all in memory at once.
+#[derive(Clone, Copy)]
+pub struct ClientRestore<'a> {
+    pub config: &'a Config,
+    // Creates the store of the transactions of a client.
+    pub new_store: &'a dyn Fn(u16) -> AnyStore,
+}
+
+impl<'de> DeserializeSeed<'de> for ClientRestore<'_> {
+    type Value = ClientState;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<ClientState, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ClientRestore<'_> {
+    type Value = ClientState;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("the snapshot of a client")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<ClientState, A::Error> {
+        // The store is created for the client, so the client has to come first.
+        let client: u16 = match map.next_key()? {
+            Some(ClientField::Client) => map.next_value()?,
+            _ => return Err(A::Error::missing_field("client")),
+        };
+        let mut client_state =
+            ClientState::with_store(client, self.config, (self.new_store)(client));
+        // Fields missing in snapshots of earlier runs (e.g. `held_back`) keep their defaults.
+        while let Some(field) = map.next_key()? {
+            match field {
+                ClientField::Client => return Err(A::Error::duplicate_field("client")),
+                ClientField::Balances => client_state.balances = map.next_value()?,
+                ClientField::Status => client_state.status = map.next_value()?,
+                ClientField::Activity => client_state.activity = map.next_value()?,
+                ClientField::Transactions => {
+                    map.next_value_seed(RestoredTransactions(&mut client_state))?
+                }
+                ClientField::TxOrder => client_state.tx_order = map.next_value()?,
+                ClientField::HeldBack => client_state.held_back = map.next_value()?,
+                ClientField::Expired => client_state.expired = map.next_value()?,
+                ClientField::Unknown => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(client_state)
+    }
+}
+
+// Inserts the transactions of a client snapshot into the store of the client, one by one while they are read.
+// A transaction that can't be stored fails the restore.
+struct RestoredTransactions<'a>(&'a mut ClientState);
+
+impl<'de> DeserializeSeed<'de> for RestoredTransactions<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for RestoredTransactions<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of transactions")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(tx) = seq.next_element::<Transaction>()? {
+            self.0
+                .transactions
+                .insert(tx.operation.tx_id, tx)
+                .map_err(|err| {
+                    A::Error::custom(format!(
+                        "Failed to store transaction {} with {}",
+                        tx.operation.tx_id, err
+                    ))
+                })?;
+            self.0.count_held(None, tx.status);
+        }
+        Ok(())
+    }
 }
 
 impl ClientState {
-    pub fn apply_operation(&mut self, operation: Operation) {
-        if self.status == ClientStatus::Frozen {
+    // Run `f` on the client state. If the store does blocking I/O, it runs on the blocking thread pool, so the
+    // worker threads of the runtime keep running the futures of the other clients.
+    pub async fn offload<T: Send + 'static>(
+        mut self,
+        f: impl FnOnce(&mut ClientState) -> T + Send + 'static,
+    ) -> (ClientState, T) {
+        if !self.transactions.does_blocking_io() {
+            let result = f(&mut self);
+            return (self, result);
+        }
+        tokio::task::spawn_blocking(move || {
+            let result = f(&mut self);
+            (self, result)
+        })
+        .await
+        .expect("Failed to run client state on the blocking thread pool")
+    }
+
+    pub fn apply_operation(&mut self, operation: Operation) -> Result<Transition, Rejection> {
+        self.check_status(operation.type_)?;
+
+        // Incoming transfers are not checked, the sender was already debited when they are applied.
+        let mut freeze = false;
+        if operation.client == self.client {
+            let matches = self.rules.evaluate(&self.activity, &operation);
+            freeze = matches
+                .iter()
+                .any(|rule_match| rule_match.action == RuleAction::Freeze);
+            let block = matches
+                .iter()
+                .any(|rule_match| rule_match.action == RuleAction::Block);
+            self.rule_matches.extend(matches);
+            if block {
+                return Err(Rejection::BlockedByRule);
+            }
+        }
+
+        // The events of a rejected operation are dropped.
+        let events = self.events.len();
+        let transition = self
+            .apply(operation)
+            .inspect_err(|_| self.events.truncate(events))?;
+        self.update_debt();
+        self.evict_transactions(operation.timestamp);
+        self.activity.record(&operation, self.rules.history());
+        if freeze && self.status == ClientStatus::Normal {
+            self.status = ClientStatus::Frozen;
+            self.emit_status(EventKind::AccountFrozen, &operation);
+        }
+        Ok(transition)
+    }
+
+    // Store a new transaction together with the balance changed by it, see `commit`.
+    fn store_transaction(
+        &mut self,
+        balance: Balance,
+        operation: Operation,
+    ) -> Result<(), Rejection> {
+        self.commit(balance, Transaction::new(operation), None)?;
+        if self.max_transactions.is_some() || self.retain_for.is_some() {
+            self.tx_order.push_back(operation.tx_id);
+        }
+        Ok(())
+    }
+
+    // Store the balance and the transaction changed by an operation. The balance is checked before the transaction
+    // is stored, and the balance only set once the transaction is stored, so neither an overflow nor a failing
+    // store leaves a partial change.
+    // `previous` is the status of the stored transaction before the change, if it was stored already.
+    fn commit(
+        &mut self,
+        balance: Balance,
+        tx: Transaction,
+        previous: Option<TransactionStatus>,
+    ) -> Result<(), Rejection> {
+        check_total(&balance)?;
+        self.transactions
+            .insert(tx.operation.tx_id, tx)
+            .map_err(storage_failed)?;
+        self.count_held(previous, tx.status);
+        // A settled transaction is older than the others, so it is the next one to be evicted.
+        if !tx.is_held() && self.held_back.remove(&tx.operation.tx_id) {
+            self.tx_order.push_front(tx.operation.tx_id);
+        }
+        self.set_balance(balance)
+    }
+
+    // Update the number of disputed and authorized transactions for a transaction changing from `previous`
+    // to `status`.
+    fn count_held(&mut self, previous: Option<TransactionStatus>, status: TransactionStatus) {
+        match previous {
+            Some(TransactionStatus::Disputed) => self.disputed -= 1,
+            Some(TransactionStatus::Authorized) => self.authorized -= 1,
+            _ => {}
+        }
+        match status {
+            TransactionStatus::Disputed => self.disputed += 1,
+            TransactionStatus::Authorized => self.authorized += 1,
+            _ => {}
+        }
+    }
+
+    // Evict the oldest transactions exceeding the retention policy. `now` is the time of the latest operation.
+    // Disputed or authorized transactions hold funds, so they are held back until they are settled.
+    fn evict_transactions(&mut self, now: Option<Timestamp>) {
+        while let Some(&tx_id) = self.tx_order.front() {
+            // The operation was already applied, so a failing store only delays the eviction.
+            let tx = match self.transactions.get(tx_id) {
+                Ok(Some(tx)) => tx,
+                Ok(None) => {
+                    self.tx_order.pop_front();
+                    continue;
+                }
+                Err(err) => {
+                    eprintln!("Failed to evict transaction {} with {}", tx_id, err);
+                    break;
+                }
+            };
+
+            let too_many = self
+                .max_transactions
+                .is_some_and(|max| self.transactions.len() > max);
+            let too_old = matches!(
+                (self.retain_for, now, tx.operation.timestamp),
+                (Some(age), Some(now), Some(time)) if now.seconds_since(time) > age
+            );
+            if !too_many && !too_old {
+                break;
+            }
+
+            self.tx_order.pop_front();
+            if tx.is_held() {
+                self.held_back.insert(tx_id);
+                continue;
+            }
+            if let Err(err) = self.transactions.remove(tx_id) {
+                eprintln!("Failed to evict transaction {} with {}", tx_id, err);
+                self.tx_order.push_front(tx_id);
+                break;
+            }
+            self.expired.insert(tx_id);
+        }
+    }
+
+    // Whether `tx_id` is free for a new transaction. The ids of stored and evicted transactions of the client are
+    // always taken, whatever the duplicate policy across clients is, so a repeated id can't replace a transaction.
+    fn check_new_tx(&self, tx_id: u32) -> Result<(), Rejection> {
+        if self.expired.contains(&tx_id)
+            || self.transactions.contains(tx_id).map_err(storage_failed)?
+        {
+            return Err(Rejection::DuplicateTx);
+        }
+        Ok(())
+    }
+
+    // The stored transaction referenced by an operation.
+    fn transaction(&self, tx_id: u32) -> Result<Transaction, Rejection> {
+        self.transactions
+            .get(tx_id)
+            .map_err(storage_failed)?
+            .ok_or_else(|| {
+                if self.expired.contains(&tx_id) {
+                    Rejection::Expired
+                } else {
+                    Rejection::UnknownTx
+                }
+            })
+    }
+
+    // Take the events of the operations applied since the last call.
+    pub fn drain_events(&mut self) -> impl Iterator<Item = Event> + '_ {
+        self.events.drain(..)
+    }
+
+    // Take the rules matched by the operations applied since the last call.
+    pub fn drain_rule_matches(&mut self) -> impl Iterator<Item = RuleMatch> + '_ {
+        self.rule_matches.drain(..)
+    }
+
+    fn update_debt(&mut self) {
+        for balance in self.balances.iter_mut() {
+            if balance.available < self.min_available {
+                balance.in_debt = true;
+            } else if balance.available >= Amount::ZERO {
+                balance.in_debt = false;
+            }
+        }
+    }
+
+    // Whether the status of the account allows operations of the given type.
+    fn check_status(&self, type_: OperationType) -> Result<(), Rejection> {
+        match (&self.status, type_) {
+            (ClientStatus::Closed, _) => Err(Rejection::AccountClosed),
+            (ClientStatus::Frozen, OperationType::Unlock | OperationType::Close) => Ok(()),
+            (ClientStatus::Frozen, _) => Err(Rejection::AccountFrozen),
+            (ClientStatus::Normal, _) => Ok(()),
+        }
+    }
+
+    // Whether the client can receive the funds of `transfer`.
+    pub fn can_receive_transfer(&self, transfer: &Operation) -> Result<(), Rejection> {
+        self.check_status(OperationType::Transfer)?;
+        let mut balance = self.balance_in(transfer.currency);
+        balance.available = add(balance.available, transfer.amount)?;
+        check_total(&balance)
+    }
+
+    // Whether `amount` (including fees) can be debited from the available funds in `currency`.
+    fn check_funds(&self, currency: Currency, amount: Amount) -> Result<(), Rejection> {
+        let balance = self.balance_in(currency);
+        if balance.in_debt {
+            return Err(Rejection::InDebt);
+        }
+        if sub(balance.available, amount)? < self.min_available {
+            return Err(Rejection::InsufficientFunds);
+        }
+        Ok(())
+    }
+
+    // Whether a dispute at the time of `dispute` is within the dispute window of the transaction `tx`.
+    // Operations without a timestamp are always within the window.
+    fn check_dispute_window(&self, tx: &Transaction, dispute: &Operation) -> Result<(), Rejection> {
+        match (
+            self.dispute_window,
+            tx.operation.timestamp,
+            dispute.timestamp,
+        ) {
+            (Some(window), Some(tx_time), Some(dispute_time))
+                if dispute_time.seconds_since(tx_time) > window =>
+            {
+                Err(Rejection::DisputeWindowExpired)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The balance in `currency`, or an empty one if the client had no funds in this currency yet.
+    fn balance_in(&self, currency: Currency) -> Balance {
+        self.balances
+            .iter()
+            .find(|balance| balance.currency == currency)
+            .copied()
+            .unwrap_or(Balance {
+                currency,
+                ..Default::default()
+            })
+    }
+
+    // Store the balance changed by an operation. Operations change a copy of the balance, so an overflow in any
+    // step leaves the client state untouched.
+    fn set_balance(&mut self, balance: Balance) -> Result<(), Rejection> {
+        check_total(&balance)?;
+        match self
+            .balances
+            .iter_mut()
+            .find(|stored| stored.currency == balance.currency)
+        {
+            Some(stored) => *stored = balance,
+            None => self.balances.push(balance),
+        }
+        Ok(())
+    }
+
+    // Record an event for a change already applied to `balance`, if events are collected.
+    fn emit(&mut self, event: EventKind, tx_id: u32, amount: Amount, balance: &Balance) {
+        if !self.collect_events {
             return;
         }
+        self.events.push(Event {
+            line: 0,
+            event,
+            client: self.client,
+            tx: tx_id,
+            currency: balance.currency,
+            amount,
+            available: balance.available,
+            held: balance.held,
+        });
+    }
+
+    // Record an event for a change of the status of the account.
+    fn emit_status(&mut self, event: EventKind, operation: &Operation) {
+        let balance = self.balance_in(operation.currency);
+        self.emit(event, operation.tx_id, Amount::ZERO, &balance);
+    }
 
+    fn charge_fee(
+        &mut self,
+        balance: &mut Balance,
+        fee: Amount,
+        tx_id: u32,
+    ) -> Result<(), Rejection> {
+        if fee == Amount::ZERO {
+            return Ok(());
+        }
+        balance.available = sub(balance.available, fee)?;
+        balance.fees = add(balance.fees, fee)?;
+        self.emit(EventKind::FeeCharged, tx_id, fee, balance);
+        Ok(())
+    }
+
+    fn apply(&mut self, operation: Operation) -> Result<Transition, Rejection> {
+        let tx_id = operation.tx_id;
         match operation.type_ {
             OperationType::Deposit => {
-                self.available += operation.amount;
-                self.transactions.insert(
-                    operation.tx_id,
-                    Transaction {
-                        operation,
-                        status: TransactionStatus::Normal,
-                    },
-                );
+                self.check_new_tx(tx_id)?;
+                let fee = fee(self.fees.deposit, operation.amount)?;
+                let mut balance = self.balance_in(operation.currency);
+                balance.available = add(balance.available, operation.amount)?;
+                self.emit(EventKind::FundsDeposited, tx_id, operation.amount, &balance);
+                self.charge_fee(&mut balance, fee, tx_id)?;
+                self.store_transaction(balance, operation)?;
+                Ok(Transition::Deposited)
             }
             OperationType::Withdrawal => {
-                if self.available >= operation.amount {
-                    self.available -= operation.amount;
+                self.check_new_tx(tx_id)?;
+                // The fee has to be covered by the available funds too.
+                let fee = fee(self.fees.withdrawal, operation.amount)?;
+                self.check_funds(operation.currency, add(operation.amount, fee)?)?;
+
+                let mut balance = self.balance_in(operation.currency);
+                balance.available = sub(balance.available, operation.amount)?;
+                self.emit(EventKind::FundsWithdrawn, tx_id, operation.amount, &balance);
+                self.charge_fee(&mut balance, fee, tx_id)?;
+                // Withdrawals are only kept if they can be disputed later on.
+                if self.dispute_policy == DisputePolicy::DepositsAndWithdrawals {
+                    self.store_transaction(balance, operation)?;
+                } else {
+                    self.set_balance(balance)?;
                 }
+                Ok(Transition::Withdrawn)
             }
             OperationType::Dispute => {
-                if let Some(tx) = self.transactions.get_mut(&operation.tx_id) {
-                    if tx.status != TransactionStatus::Disputed {
-                        self.available -= tx.operation.amount;
-                        self.held += tx.operation.amount;
-                        tx.status = TransactionStatus::Disputed;
-                    }
-                }
+                let mut tx = self.transaction(tx_id)?;
+                let previous = tx.status;
+                self.check_dispute_window(&tx, &operation)?;
+                let amount = tx.dispute(operation.amount)?;
+                // Disputes always apply to the currency of the disputed transaction.
+                let mut balance = self.balance_in(tx.operation.currency);
+                // A disputed deposit holds the deposited funds. A disputed withdrawal
+                // holds the withdrawn amount without making it available again.
+                let event = if tx.operation.type_ == OperationType::Deposit {
+                    balance.available = sub(balance.available, amount)?;
+                    EventKind::FundsHeld
+                } else {
+                    EventKind::WithdrawalHeld
+                };
+                balance.held = add(balance.held, amount)?;
+                self.emit(event, tx_id, amount, &balance);
+                self.commit(balance, tx, Some(previous))?;
+                Ok(Transition::Disputed)
             }
             OperationType::Resolve => {
-                if let Some(tx) = self.transactions.get_mut(&operation.tx_id) {
-                    if tx.status == TransactionStatus::Disputed {
-                        self.available += tx.operation.amount;
-                        self.held -= tx.operation.amount;
-                        tx.status = TransactionStatus::Normal;
-                    }
-                }
+                let mut tx = self.transaction(tx_id)?;
+                let previous = tx.status;
+                let amount = tx.resolve(operation.amount)?;
+                let mut balance = self.balance_in(tx.operation.currency);
+                // A resolved deposit is available again, a resolved withdrawal stands.
+                let event = if tx.operation.type_ == OperationType::Deposit {
+                    balance.available = add(balance.available, amount)?;
+                    EventKind::FundsReleased
+                } else {
+                    EventKind::WithdrawalReleased
+                };
+                balance.held = sub(balance.held, amount)?;
+                self.emit(event, tx_id, amount, &balance);
+                self.commit(balance, tx, Some(previous))?;
+                Ok(Transition::Resolved)
             }
             OperationType::Chargeback => {
-                if let Some(tx) = self.transactions.get_mut(&operation.tx_id) {
-                    if tx.status == TransactionStatus::Disputed {
-                        self.held -= tx.operation.amount;
-                        self.status = ClientStatus::Frozen;
-                        tx.status = TransactionStatus::Normal;
-                    }
+                let mut tx = self.transaction(tx_id)?;
+                let previous = tx.status;
+                let amount = tx.chargeback(operation.amount)?;
+                let mut balance = self.balance_in(tx.operation.currency);
+                // A charged back withdrawal is credited back to the client.
+                let event = if tx.operation.type_ == OperationType::Withdrawal {
+                    balance.available = add(balance.available, amount)?;
+                    EventKind::WithdrawalChargedBack
+                } else {
+                    EventKind::ChargedBack
+                };
+                balance.held = sub(balance.held, amount)?;
+                self.emit(event, tx_id, amount, &balance);
+                // The chargeback fee is charged even if the client can't cover it.
+                let fee = fee(self.fees.chargeback, amount)?;
+                self.charge_fee(&mut balance, fee, tx_id)?;
+                self.commit(balance, tx, Some(previous))?;
+                self.status = ClientStatus::Frozen;
+                self.emit(EventKind::AccountFrozen, tx_id, Amount::ZERO, &balance);
+                Ok(Transition::ChargedBack)
+            }
+            // A transfer is applied to both clients: the sender is debited, the receiver credited.
+            // The caller has to make sure the receiver is only credited if the sender was debited.
+            OperationType::Transfer if operation.client == self.client => {
+                self.check_funds(operation.currency, operation.amount)?;
+                let mut balance = self.balance_in(operation.currency);
+                balance.available = sub(balance.available, operation.amount)?;
+                self.emit(EventKind::TransferredOut, tx_id, operation.amount, &balance);
+                self.set_balance(balance)?;
+                Ok(Transition::TransferredOut)
+            }
+            OperationType::Transfer => {
+                if operation.to != Some(self.client) {
+                    return Err(Rejection::InvalidTransfer);
                 }
+                let mut balance = self.balance_in(operation.currency);
+                balance.available = add(balance.available, operation.amount)?;
+                self.emit(EventKind::TransferredIn, tx_id, operation.amount, &balance);
+                self.set_balance(balance)?;
+                Ok(Transition::TransferredIn)
             }
-        };
+            OperationType::Refund => {
+                let mut tx = self.transaction(tx_id)?;
+                let previous = tx.status;
+                let amount = tx.refundable_amount(operation.amount)?;
+                let currency = tx.operation.currency;
+                // The refunded funds leave the client like a withdrawal.
+                self.check_funds(currency, amount)?;
+
+                let mut balance = self.balance_in(currency);
+                balance.available = sub(balance.available, amount)?;
+                self.emit(EventKind::FundsRefunded, tx_id, amount, &balance);
+                tx.refunded = add(tx.refunded, amount)?;
+                self.commit(balance, tx, Some(previous))?;
+                Ok(Transition::Refunded)
+            }
+            OperationType::Authorize => {
+                self.check_new_tx(tx_id)?;
+                // The authorized funds are held until the authorization is captured or voided.
+                self.check_funds(operation.currency, operation.amount)?;
+                let mut balance = self.balance_in(operation.currency);
+                balance.available = sub(balance.available, operation.amount)?;
+                balance.held = add(balance.held, operation.amount)?;
+                self.emit(EventKind::FundsHeld, tx_id, operation.amount, &balance);
+                self.store_transaction(balance, operation)?;
+                Ok(Transition::Authorized)
+            }
+            OperationType::Capture => {
+                let mut tx = self.transaction(tx_id)?;
+                let previous = tx.status;
+                let captured = tx.capture(operation.amount)?;
+                // The captured funds leave the client, the rest of the authorization is available again.
+                let mut balance = self.balance_in(tx.operation.currency);
+                balance.held = sub(balance.held, captured)?;
+                self.emit(EventKind::FundsCaptured, tx_id, captured, &balance);
+                let rest = sub(tx.operation.amount, captured)?;
+                if rest != Amount::ZERO {
+                    balance.held = sub(balance.held, rest)?;
+                    balance.available = add(balance.available, rest)?;
+                    self.emit(EventKind::FundsReleased, tx_id, rest, &balance);
+                }
+                self.commit(balance, tx, Some(previous))?;
+                Ok(Transition::Captured)
+            }
+            OperationType::Void => {
+                let mut tx = self.transaction(tx_id)?;
+                let previous = tx.status;
+                let released = tx.void()?;
+                let mut balance = self.balance_in(tx.operation.currency);
+                balance.held = sub(balance.held, released)?;
+                balance.available = add(balance.available, released)?;
+                self.emit(EventKind::FundsReleased, tx_id, released, &balance);
+                self.commit(balance, tx, Some(previous))?;
+                Ok(Transition::Voided)
+            }
+            OperationType::Unlock => {
+                // Pending disputes stay open and can be resolved or charged back after unlocking.
+                if self.status != ClientStatus::Frozen {
+                    return Err(Rejection::NotFrozen);
+                }
+                self.status = ClientStatus::Normal;
+                self.emit_status(EventKind::AccountUnlocked, &operation);
+                Ok(Transition::Unlocked)
+            }
+            OperationType::Close => {
+                // Held funds must be released before the account can be closed.
+                if self.disputed > 0 {
+                    return Err(Rejection::PendingDisputes);
+                }
+                if self.authorized > 0 {
+                    return Err(Rejection::PendingAuthorizations);
+                }
+                self.status = ClientStatus::Closed;
+                self.emit_status(EventKind::AccountClosed, &operation);
+                Ok(Transition::Closed)
+            }
+        }
+    }
+
+    // The balance in the default currency.
+    #[cfg(test)]
+    fn balance(&self) -> Balance {
+        self.balances
+            .iter()
+            .find(|balance| balance.currency == Currency::default())
+            .copied()
+            .unwrap_or_default()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::client_state::ClientStatus;
+    use std::collections::{HashSet, VecDeque};
+    use std::io;
+
+    use crate::amount::Amount;
+    use crate::client_state::{Balance, ClientStatus, Rejection, Transition};
+    use crate::config::{Config, DisputePolicy, RetentionAge, RetentionPolicy};
+    use crate::event::{Event, EventKind, EventSink, SharedEventSink};
+    use crate::fee::read_fee_schedule;
+    use crate::rule::{read_rules, RuleAction, RuleMatch};
+    use crate::transaction::{Transaction, TransactionStatus};
+    use crate::transaction_store::{AnyStore, MemoryStore, TransactionStore};
     use crate::{ClientState, Operation};
 
+    // Only enables collecting events, see `test_events`.
+    #[derive(Debug)]
+    struct NoSink;
+
+    impl EventSink for NoSink {
+        fn publish(&self, _: &[Event]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
     fn frozen_account() -> ClientState {
         let mut client_state = ClientState::new(0);
         vec![
@@ -137,7 +991,9 @@ mod tests {
             Operation::chargeback(0, 2),
         ]
         .drain(..)
-        .for_each(|operation| client_state.apply_operation(operation));
+        .for_each(|operation| {
+            client_state.apply_operation(operation).unwrap();
+        });
 
         client_state
     }
@@ -147,51 +1003,75 @@ mod tests {
         let mut frozen = frozen_account();
         assert_eq!(frozen.status, ClientStatus::Frozen);
         let original = frozen.clone();
-        frozen.apply_operation(Operation::deposit(0, 4, 10));
-        assert_eq!(frozen, original);
-        frozen.apply_operation(Operation::withdrawal(0, 5, 5));
-        assert_eq!(frozen, original);
-        frozen.apply_operation(Operation::dispute(0, 1));
-        assert_eq!(frozen, original);
-        frozen.apply_operation(Operation::resolve(0, 2));
-        assert_eq!(frozen, original);
-        frozen.apply_operation(Operation::chargeback(0, 2));
+        for operation in [
+            Operation::deposit(0, 4, 10),
+            Operation::withdrawal(0, 5, 5),
+            Operation::dispute(0, 1),
+            Operation::resolve(0, 2),
+            Operation::chargeback(0, 2),
+        ] {
+            assert_eq!(
+                frozen.apply_operation(operation),
+                Err(Rejection::AccountFrozen)
+            );
+            assert_eq!(frozen, original);
+        }
     }
 
     #[test]
     fn test_cant_withdraw_more_than_available() {
         let mut client = ClientState::new(0);
-        client.apply_operation(Operation::deposit(0, 1, 25));
-        client.apply_operation(Operation::deposit(0, 2, 25));
+        client
+            .apply_operation(Operation::deposit(0, 1, 25))
+            .unwrap();
+        client
+            .apply_operation(Operation::deposit(0, 2, 25))
+            .unwrap();
         {
             let state = client.clone();
             let mut client = client.clone();
 
-            client.apply_operation(Operation::withdrawal(0, 3, 51));
+            assert_eq!(
+                client.apply_operation(Operation::withdrawal(0, 3, 51)),
+                Err(Rejection::InsufficientFunds)
+            );
             // Operation was not applied!
             assert_eq!(state, client);
-            client.apply_operation(Operation::withdrawal(0, 3, 50));
-            assert_eq!(client.available, 0);
+            assert_eq!(
+                client.apply_operation(Operation::withdrawal(0, 3, 50)),
+                Ok(Transition::Withdrawn)
+            );
+            assert_eq!(client.balance().available, Amount::ZERO);
         }
 
         {
             let mut client = client.clone();
-            client.apply_operation(Operation::dispute(0, 1));
+            client.apply_operation(Operation::dispute(0, 1)).unwrap();
             let state = client.clone();
-            client.apply_operation(Operation::withdrawal(0, 4, 26));
+            assert_eq!(
+                client.apply_operation(Operation::withdrawal(0, 4, 26)),
+                Err(Rejection::InsufficientFunds)
+            );
             // Operation was not applied!
             assert_eq!(state, client);
-            client.apply_operation(Operation::withdrawal(0, 4, 25));
-            assert_eq!(client.available, 0);
+            client
+                .apply_operation(Operation::withdrawal(0, 4, 25))
+                .unwrap();
+            assert_eq!(client.balance().available, Amount::ZERO);
         }
     }
 
     #[test]
     fn test_can_only_dispute_existing_transactions() {
         let mut client = ClientState::new(0);
-        client.apply_operation(Operation::deposit(0, 1, 25));
+        client
+            .apply_operation(Operation::deposit(0, 1, 25))
+            .unwrap();
         let state = client.clone();
-        client.apply_operation(Operation::dispute(0, 2));
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 2)),
+            Err(Rejection::UnknownTx)
+        );
         // Operation was not applied!
         assert_eq!(state, client);
     }
@@ -199,21 +1079,43 @@ mod tests {
     #[test]
     fn test_resolving_is_inverse_of_dispute() {
         let mut client = ClientState::new(0);
-        client.apply_operation(Operation::deposit(0, 1, 25));
+        client
+            .apply_operation(Operation::deposit(0, 1, 25))
+            .unwrap();
         let state = client.clone();
-        client.apply_operation(Operation::dispute(0, 1));
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1)),
+            Ok(Transition::Disputed)
+        );
         assert_ne!(state, client);
-        client.apply_operation(Operation::resolve(0, 1));
-        // State after resolving a dispute is equal to the state before the dispute (if no operations are inbetween)
-        assert_eq!(state, client);
+        assert_eq!(
+            client.apply_operation(Operation::resolve(0, 1)),
+            Ok(Transition::Resolved)
+        );
+        // The balances after resolving a dispute are equal to the balances before the dispute (if no operations are inbetween)
+        assert_eq!(state.balance().available, client.balance().available);
+        assert_eq!(state.balance().held, client.balance().held);
+        assert_eq!(
+            client.transactions.get(1).unwrap().unwrap().status,
+            TransactionStatus::Resolved
+        );
     }
 
     #[test]
     fn test_can_only_resolve_disputes() {
         let mut client = ClientState::new(0);
-        client.apply_operation(Operation::deposit(0, 1, 25));
+        client
+            .apply_operation(Operation::deposit(0, 1, 25))
+            .unwrap();
         let state = client.clone();
-        client.apply_operation(Operation::resolve(0, 2));
+        assert_eq!(
+            client.apply_operation(Operation::resolve(0, 2)),
+            Err(Rejection::UnknownTx)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::resolve(0, 1)),
+            Err(Rejection::NotDisputed)
+        );
         // Operation was not applied!
         assert_eq!(state, client);
     }
@@ -221,21 +1123,1163 @@ mod tests {
     #[test]
     fn test_dispute_can_only_be_applied_once() {
         let mut client = ClientState::new(0);
-        client.apply_operation(Operation::deposit(0, 1, 25));
-        client.apply_operation(Operation::dispute(0, 1));
+        client
+            .apply_operation(Operation::deposit(0, 1, 25))
+            .unwrap();
+        client.apply_operation(Operation::dispute(0, 1)).unwrap();
         let state = client.clone();
-        client.apply_operation(Operation::dispute(0, 1));
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1)),
+            Err(Rejection::AlreadyDisputed)
+        );
         // Operation was not applied!
         assert_eq!(state, client);
     }
 
+    #[test]
+    fn test_resolved_transaction_can_be_disputed_again() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 25))
+            .unwrap();
+        client.apply_operation(Operation::dispute(0, 1)).unwrap();
+        client.apply_operation(Operation::resolve(0, 1)).unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1)),
+            Ok(Transition::Disputed)
+        );
+        assert_eq!(client.balance().held, Amount::from_units(25));
+    }
+
     #[test]
     fn test_can_only_chargeback_disputes() {
         let mut client = ClientState::new(0);
-        client.apply_operation(Operation::deposit(0, 1, 25));
+        client
+            .apply_operation(Operation::deposit(0, 1, 25))
+            .unwrap();
+        let state = client.clone();
+        assert_eq!(
+            client.apply_operation(Operation::chargeback(0, 2)),
+            Err(Rejection::UnknownTx)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::chargeback(0, 1)),
+            Err(Rejection::NotDisputed)
+        );
+        // Operation was not applied!
+        assert_eq!(state, client);
+    }
+
+    #[test]
+    fn test_charged_back_is_final() {
+        let mut frozen = frozen_account();
+        frozen.apply_operation(Operation::unlock(0, 4)).unwrap();
+        assert_eq!(
+            frozen.transactions.get(2).unwrap().unwrap().status,
+            TransactionStatus::ChargedBack
+        );
+        for operation in [
+            Operation::dispute(0, 2),
+            Operation::resolve(0, 2),
+            Operation::chargeback(0, 2),
+        ] {
+            assert_eq!(
+                frozen.apply_operation(operation),
+                Err(Rejection::AlreadyChargedBack)
+            );
+        }
+    }
+
+    #[test]
+    fn test_partial_dispute_holds_amount() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 100))
+            .unwrap();
+        client
+            .apply_operation(Operation::dispute(0, 1).with_amount(30))
+            .unwrap();
+        assert_eq!(client.balance().available, Amount::from_units(70));
+        assert_eq!(client.balance().held, Amount::from_units(30));
+        client
+            .apply_operation(Operation::resolve(0, 1).with_amount(10))
+            .unwrap();
+        assert_eq!(client.balance().available, Amount::from_units(80));
+        assert_eq!(client.balance().held, Amount::from_units(20));
+        assert_eq!(
+            client.transactions.get(1).unwrap().unwrap().status,
+            TransactionStatus::Disputed
+        );
+        // Resolving without an amount resolves everything that is disputed.
+        client.apply_operation(Operation::resolve(0, 1)).unwrap();
+        assert_eq!(client.balance().available, Amount::from_units(100));
+        assert_eq!(client.balance().held, Amount::ZERO);
+        assert_eq!(
+            client.transactions.get(1).unwrap().unwrap().status,
+            TransactionStatus::Resolved
+        );
+    }
+
+    #[test]
+    fn test_partial_disputes_cant_exceed_amount() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 100))
+            .unwrap();
+        client
+            .apply_operation(Operation::dispute(0, 1).with_amount(60))
+            .unwrap();
+        let state = client.clone();
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1).with_amount(41)),
+            Err(Rejection::ExceedsRemainder)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::resolve(0, 1).with_amount(61)),
+            Err(Rejection::ExceedsDisputed)
+        );
+        assert_eq!(state, client);
+
+        // Disputing without an amount disputes the remainder.
+        client.apply_operation(Operation::dispute(0, 1)).unwrap();
+        assert_eq!(client.balance().held, Amount::from_units(100));
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1).with_amount(1)),
+            Err(Rejection::AlreadyDisputed)
+        );
+    }
+
+    #[test]
+    fn test_partial_chargeback() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 100))
+            .unwrap();
+        client
+            .apply_operation(Operation::dispute(0, 1).with_amount(60))
+            .unwrap();
+        client
+            .apply_operation(Operation::chargeback(0, 1).with_amount(20))
+            .unwrap();
+        assert_eq!(client.status, ClientStatus::Frozen);
+        assert_eq!(client.balance().available, Amount::from_units(40));
+        assert_eq!(client.balance().held, Amount::from_units(40));
+        assert_eq!(
+            client.transactions.get(1).unwrap().unwrap().status,
+            TransactionStatus::Disputed
+        );
+
+        client.apply_operation(Operation::unlock(0, 2)).unwrap();
+        // The charged back part can't be disputed again.
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1).with_amount(41)),
+            Err(Rejection::ExceedsRemainder)
+        );
+        client.apply_operation(Operation::resolve(0, 1)).unwrap();
+        assert_eq!(client.balance().available, Amount::from_units(80));
+        assert_eq!(client.balance().held, Amount::ZERO);
+        // Once the dispute ends, a partially charged back transaction is final.
+        assert_eq!(
+            client.transactions.get(1).unwrap().unwrap().status,
+            TransactionStatus::ChargedBack
+        );
+    }
+
+    #[test]
+    fn test_unlock_reinstates_frozen_account() {
+        let mut client = frozen_account();
+        assert_eq!(
+            client.apply_operation(Operation::unlock(0, 4)),
+            Ok(Transition::Unlocked)
+        );
+        assert_eq!(client.status, ClientStatus::Normal);
+        assert_eq!(
+            client.apply_operation(Operation::deposit(0, 5, 10)),
+            Ok(Transition::Deposited)
+        );
+        assert_eq!(client.balance().available, Amount::from_units(15));
+        // The charged back transaction stays charged back.
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 2)),
+            Err(Rejection::AlreadyChargedBack)
+        );
+    }
+
+    #[test]
+    fn test_can_only_unlock_frozen_accounts() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 25))
+            .unwrap();
+        let state = client.clone();
+        assert_eq!(
+            client.apply_operation(Operation::unlock(0, 2)),
+            Err(Rejection::NotFrozen)
+        );
+        assert_eq!(state, client);
+    }
+
+    #[test]
+    fn test_pending_disputes_survive_unlock() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 10))
+            .unwrap();
+        client
+            .apply_operation(Operation::deposit(0, 2, 10))
+            .unwrap();
+        client.apply_operation(Operation::dispute(0, 1)).unwrap();
+        client.apply_operation(Operation::dispute(0, 2)).unwrap();
+        client.apply_operation(Operation::chargeback(0, 2)).unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::resolve(0, 1)),
+            Err(Rejection::AccountFrozen)
+        );
+
+        client.apply_operation(Operation::unlock(0, 3)).unwrap();
+        assert_eq!(client.balance().held, Amount::from_units(10));
+        assert_eq!(
+            client.apply_operation(Operation::resolve(0, 1)),
+            Ok(Transition::Resolved)
+        );
+        assert_eq!(client.balance().available, Amount::from_units(10));
+        assert_eq!(client.balance().held, Amount::ZERO);
+    }
+
+    #[test]
+    fn test_close_requires_resolved_disputes() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 10))
+            .unwrap();
+        client.apply_operation(Operation::dispute(0, 1)).unwrap();
+        let state = client.clone();
+        assert_eq!(
+            client.apply_operation(Operation::close(0, 2)),
+            Err(Rejection::PendingDisputes)
+        );
+        assert_eq!(state, client);
+
+        client.apply_operation(Operation::resolve(0, 1)).unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::close(0, 2)),
+            Ok(Transition::Closed)
+        );
+    }
+
+    #[test]
+    fn test_closed_ignores_operations() {
+        let mut closed = frozen_account();
+        closed.apply_operation(Operation::close(0, 4)).unwrap();
+        assert_eq!(closed.status, ClientStatus::Closed);
+        let original = closed.clone();
+        for operation in [
+            Operation::deposit(0, 5, 10),
+            Operation::withdrawal(0, 6, 5),
+            Operation::dispute(0, 1),
+            Operation::unlock(0, 7),
+            Operation::close(0, 8),
+        ] {
+            assert_eq!(
+                closed.apply_operation(operation),
+                Err(Rejection::AccountClosed)
+            );
+            assert_eq!(closed, original);
+        }
+    }
+
+    fn client_with_credit_limit(limit: i128) -> ClientState {
+        let config = Config {
+            credit_limits: [(0, Amount::from_units(limit))].into_iter().collect(),
+            ..Default::default()
+        };
+        ClientState::with_config(0, &config)
+    }
+
+    #[test]
+    fn test_withdrawal_can_overdraw_up_to_credit_limit() {
+        let mut client = client_with_credit_limit(20);
+        client
+            .apply_operation(Operation::deposit(0, 1, 10))
+            .unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::withdrawal(0, 2, 31)),
+            Err(Rejection::InsufficientFunds)
+        );
+        client
+            .apply_operation(Operation::withdrawal(0, 2, 30))
+            .unwrap();
+        assert_eq!(client.balance().available, Amount::from_units(-20));
+        // Using the credit is not a debt.
+        assert!(!client.balance().in_debt);
+    }
+
+    #[test]
+    fn test_debt_blocks_withdrawals_until_balance_recovers() {
+        let mut client = client_with_credit_limit(20);
+        client
+            .apply_operation(Operation::deposit(0, 1, 50))
+            .unwrap();
+        client
+            .apply_operation(Operation::withdrawal(0, 2, 40))
+            .unwrap();
+        client.apply_operation(Operation::dispute(0, 1)).unwrap();
+        assert_eq!(client.balance().available, Amount::from_units(-40));
+        assert!(client.balance().in_debt);
+        let state = client.clone();
+        assert_eq!(
+            client.apply_operation(Operation::withdrawal(0, 3, 1)),
+            Err(Rejection::InDebt)
+        );
+        assert_eq!(state, client);
+
+        // Back within the credit limit is not enough, the balance has to recover.
+        client
+            .apply_operation(Operation::deposit(0, 4, 30))
+            .unwrap();
+        assert_eq!(client.balance().available, Amount::from_units(-10));
+        assert!(client.balance().in_debt);
+        client
+            .apply_operation(Operation::deposit(0, 5, 10))
+            .unwrap();
+        assert!(!client.balance().in_debt);
+        client
+            .apply_operation(Operation::withdrawal(0, 6, 20))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_balances_per_currency() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 10).with_currency("EUR"))
+            .unwrap();
+        client
+            .apply_operation(Operation::deposit(0, 2, 20).with_currency("USD"))
+            .unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::withdrawal(0, 3, 11).with_currency("EUR")),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::withdrawal(0, 3, 1)),
+            Err(Rejection::InsufficientFunds)
+        );
+
+        // The dispute uses the currency of the deposit, whatever the currency column of the dispute says.
+        client
+            .apply_operation(Operation::dispute(0, 2).with_currency("EUR"))
+            .unwrap();
+        let rows = client.csv_rows().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].currency.as_str(), "EUR");
+        assert_eq!(
+            (rows[0].available, rows[0].held),
+            (Amount::from_units(10), Amount::ZERO)
+        );
+        assert_eq!(rows[1].currency.as_str(), "USD");
+        assert_eq!(
+            (rows[1].available, rows[1].held),
+            (Amount::ZERO, Amount::from_units(20))
+        );
+    }
+
+    #[test]
+    fn test_fees_are_deducted() {
+        let mut fee_schedule = read_fee_schedule("fees.csv").unwrap();
+        fee_schedule.set_tiers([(1, "gold".to_string())].into_iter().collect());
+        let config = Config {
+            fee_schedule,
+            ..Default::default()
+        };
+
+        let mut client = ClientState::with_config(0, &config);
+        client
+            .apply_operation(Operation::deposit(0, 1, 1000000))
+            .unwrap();
+        client
+            .apply_operation(Operation::deposit(0, 2, 1000000))
+            .unwrap();
+        // Withdrawing everything leaves nothing for the fee.
+        assert_eq!(
+            client.apply_operation(Operation::withdrawal(0, 3, 1000000)),
+            Ok(Transition::Withdrawn)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::withdrawal(0, 4, 1000000)),
+            Err(Rejection::InsufficientFunds)
+        );
+        // 100.0 - 1.0 - 1%
+        assert_eq!(client.balance().available, Amount::from_units(980000));
+        client.apply_operation(Operation::dispute(0, 1)).unwrap();
+        client.apply_operation(Operation::chargeback(0, 1)).unwrap();
+        assert_eq!(
+            client.balance().available,
+            Amount::from_units(-20000 - 150000)
+        );
+        assert_eq!(client.balance().fees, Amount::from_units(20000 + 150000));
+
+        // Gold clients pay less.
+        let mut client = ClientState::with_config(1, &config);
+        client
+            .apply_operation(Operation::deposit(1, 1, 1000000))
+            .unwrap();
+        client
+            .apply_operation(Operation::withdrawal(1, 2, 500000))
+            .unwrap();
+        assert_eq!(client.balance().available, Amount::from_units(495000));
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut sender = ClientState::new(0);
+        let mut receiver = ClientState::new(1);
+        sender
+            .apply_operation(Operation::deposit(0, 1, 10))
+            .unwrap();
+        assert_eq!(
+            sender.apply_operation(Operation::transfer(0, 2, 11, 1)),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(
+            sender.apply_operation(Operation::transfer(0, 2, 4, 1)),
+            Ok(Transition::TransferredOut)
+        );
+        assert_eq!(
+            receiver.can_receive_transfer(&Operation::transfer(0, 2, 4, 1)),
+            Ok(())
+        );
+        assert_eq!(
+            receiver.apply_operation(Operation::transfer(0, 2, 4, 1)),
+            Ok(Transition::TransferredIn)
+        );
+        assert_eq!(sender.balance().available, Amount::from_units(6));
+        assert_eq!(receiver.balance().available, Amount::from_units(4));
+
+        // Transfers are not disputable.
+        assert_eq!(
+            sender.apply_operation(Operation::dispute(0, 2)),
+            Err(Rejection::UnknownTx)
+        );
+        // A transfer to another client can't be applied to a third client.
+        assert_eq!(
+            ClientState::new(2).apply_operation(Operation::transfer(0, 2, 4, 1)),
+            Err(Rejection::InvalidTransfer)
+        );
+        assert_eq!(
+            frozen_account().can_receive_transfer(&Operation::transfer(0, 2, 4, 1)),
+            Err(Rejection::AccountFrozen)
+        );
+    }
+
+    #[test]
+    fn test_authorization_holds_funds() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 10))
+            .unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::authorize(0, 2, 11)),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::authorize(0, 2, 6)),
+            Ok(Transition::Authorized)
+        );
+        assert_eq!(client.balance().available, Amount::from_units(4));
+        assert_eq!(client.balance().held, Amount::from_units(6));
+        assert_eq!(
+            client.transactions.get(2).unwrap().unwrap().status,
+            TransactionStatus::Authorized
+        );
+        // Held funds can't be withdrawn.
+        assert_eq!(
+            client.apply_operation(Operation::withdrawal(0, 3, 5)),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 2)),
+            Err(Rejection::NotDisputable)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::close(0, 4)),
+            Err(Rejection::PendingAuthorizations)
+        );
+    }
+
+    #[test]
+    fn test_partial_capture_releases_rest() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 10))
+            .unwrap();
+        client
+            .apply_operation(Operation::authorize(0, 2, 6))
+            .unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::capture(0, 2).with_amount(7)),
+            Err(Rejection::ExceedsAuthorized)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::capture(0, 2).with_amount(5)),
+            Ok(Transition::Captured)
+        );
+        assert_eq!(client.balance().available, Amount::from_units(5));
+        assert_eq!(client.balance().held, Amount::ZERO);
+        assert_eq!(
+            client.transactions.get(2).unwrap().unwrap().status,
+            TransactionStatus::Captured
+        );
+
+        // A captured authorization is final.
+        for operation in [Operation::capture(0, 2), Operation::void(0, 2)] {
+            assert_eq!(
+                client.apply_operation(operation),
+                Err(Rejection::NotAuthorized)
+            );
+        }
+    }
+
+    #[test]
+    fn test_void_releases_authorization() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 10))
+            .unwrap();
+        client
+            .apply_operation(Operation::authorize(0, 2, 6))
+            .unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::void(0, 2)),
+            Ok(Transition::Voided)
+        );
+        assert_eq!(client.balance().available, Amount::from_units(10));
+        assert_eq!(client.balance().held, Amount::ZERO);
+        assert_eq!(
+            client.transactions.get(2).unwrap().unwrap().status,
+            TransactionStatus::Voided
+        );
+        assert_eq!(
+            client.apply_operation(Operation::capture(0, 2)),
+            Err(Rejection::NotAuthorized)
+        );
+        // Only authorizations can be captured.
+        assert_eq!(
+            client.apply_operation(Operation::capture(0, 1)),
+            Err(Rejection::NotAuthorized)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::close(0, 3)),
+            Ok(Transition::Closed)
+        );
+    }
+
+    #[test]
+    fn test_refund_reduces_remainder() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 10))
+            .unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::refund(0, 1).with_amount(11)),
+            Err(Rejection::ExceedsRemainder)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::refund(0, 1).with_amount(4)),
+            Ok(Transition::Refunded)
+        );
+        assert_eq!(client.balance().available, Amount::from_units(6));
+        // Only the rest of the deposit can be disputed.
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1).with_amount(7)),
+            Err(Rejection::ExceedsRemainder)
+        );
+        client
+            .apply_operation(Operation::dispute(0, 1).with_amount(2))
+            .unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::refund(0, 1).with_amount(5)),
+            Err(Rejection::ExceedsRemainder)
+        );
+        // The rest of a partially disputed deposit can still be refunded.
+        client.apply_operation(Operation::refund(0, 1)).unwrap();
+        assert_eq!(client.balance().available, Amount::ZERO);
+        assert_eq!(client.balance().held, Amount::from_units(2));
+        assert_eq!(
+            client.apply_operation(Operation::refund(0, 1)),
+            Err(Rejection::ExceedsRemainder)
+        );
+
+        client.apply_operation(Operation::resolve(0, 1)).unwrap();
+        client.apply_operation(Operation::refund(0, 1)).unwrap();
+        assert_eq!(client.balance(), Balance::default());
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1)),
+            Err(Rejection::ExceedsRemainder)
+        );
+    }
+
+    #[test]
+    fn test_can_only_refund_deposits() {
+        let mut client = disputable_withdrawals();
+        let original = client.clone();
+        for (operation, rejection) in [
+            (Operation::refund(0, 2), Rejection::NotRefundable),
+            (Operation::refund(0, 3), Rejection::UnknownTx),
+            // A refund can't overdraw the available funds.
+            (
+                Operation::refund(0, 1).with_amount(6),
+                Rejection::InsufficientFunds,
+            ),
+        ] {
+            assert_eq!(client.apply_operation(operation), Err(rejection));
+            assert_eq!(client, original);
+        }
+    }
+
+    #[test]
+    fn test_dispute_window() {
+        let config = Config {
+            dispute_window: Some(86400),
+            ..Default::default()
+        };
+        let mut client = ClientState::with_config(0, &config);
+        client
+            .apply_operation(Operation::deposit(0, 1, 10).with_timestamp("2022-01-01"))
+            .unwrap();
+        client
+            .apply_operation(Operation::deposit(0, 2, 10).with_timestamp("2022-01-01"))
+            .unwrap();
+        client
+            .apply_operation(Operation::deposit(0, 3, 10))
+            .unwrap();
+
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1).with_timestamp("2022-01-02T00:00:01Z")),
+            Err(Rejection::DisputeWindowExpired)
+        );
+        assert_eq!(
+            client.transactions.get(1).unwrap().unwrap().status,
+            TransactionStatus::Settled
+        );
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 2).with_timestamp("2022-01-02")),
+            Ok(Transition::Disputed)
+        );
+        // Resolving is not limited by the window.
+        assert_eq!(
+            client.apply_operation(Operation::resolve(0, 2).with_timestamp("2022-02-01")),
+            Ok(Transition::Resolved)
+        );
+        // Without timestamps, the window can't be checked.
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1)),
+            Ok(Transition::Disputed)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 3).with_timestamp("2022-02-01")),
+            Ok(Transition::Disputed)
+        );
+    }
+
+    #[test]
+    fn test_rules_block_flag_and_freeze() {
+        let config = Config {
+            rules: read_rules("rules.csv").unwrap(),
+            ..Default::default()
+        };
+        let mut client = ClientState::with_config(0, &config);
+        client
+            .apply_operation(Operation::deposit(0, 1, 50000000))
+            .unwrap();
+        assert_eq!(client.drain_rule_matches().count(), 0);
+
+        // A withdrawal right after a large deposit is only flagged.
+        assert_eq!(
+            client.apply_operation(Operation::withdrawal(0, 2, 10000)),
+            Ok(Transition::Withdrawn)
+        );
+        let matches: Vec<RuleMatch> = client.drain_rule_matches().collect();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].action, RuleAction::Flag);
+
+        // The 4th withdrawal within 5 operations is blocked.
+        for tx in 3..5 {
+            client
+                .apply_operation(Operation::withdrawal(0, tx, 10000))
+                .unwrap();
+        }
+        let state = client.clone();
+        assert_eq!(
+            client.apply_operation(Operation::withdrawal(0, 5, 10000)),
+            Err(Rejection::BlockedByRule)
+        );
+        assert_eq!(client.balance(), state.balance());
+        assert_eq!(client.drain_rule_matches().last().unwrap().rule, "velocity");
+
+        // Disputing more than half of the deposits freezes the account after the dispute.
+        client
+            .apply_operation(Operation::deposit(0, 6, 10000))
+            .unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 6)),
+            Ok(Transition::Disputed)
+        );
+        assert_eq!(client.status, ClientStatus::Normal);
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1).with_amount(10000)),
+            Ok(Transition::Disputed)
+        );
+        assert_eq!(client.status, ClientStatus::Frozen);
+        assert_eq!(
+            client.drain_rule_matches().last().unwrap().action,
+            RuleAction::Freeze
+        );
+    }
+
+    #[test]
+    fn test_retention_by_count() {
+        let config = Config {
+            retention: RetentionPolicy {
+                max_transactions: Some(2),
+                max_age: None,
+            },
+            ..Default::default()
+        };
+        let mut client = ClientState::with_config(0, &config);
+        client
+            .apply_operation(Operation::deposit(0, 1, 10))
+            .unwrap();
+        client.apply_operation(Operation::dispute(0, 1)).unwrap();
+        for tx in 2..5 {
+            client
+                .apply_operation(Operation::deposit(0, tx, 10))
+                .unwrap();
+        }
+
+        // The disputed transaction is kept, the oldest undisputed ones are evicted.
+        assert_eq!(client.transactions.len(), 2);
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 2)),
+            Err(Rejection::Expired)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 5)),
+            Err(Rejection::UnknownTx)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::resolve(0, 1)),
+            Ok(Transition::Resolved)
+        );
+        client
+            .apply_operation(Operation::deposit(0, 5, 10))
+            .unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1)),
+            Err(Rejection::Expired)
+        );
+        assert_eq!(client.balance().available, Amount::from_units(50));
+    }
+
+    #[test]
+    fn test_retention_by_dispute_window() {
+        let config = Config {
+            dispute_window: Some(86400),
+            retention: RetentionPolicy {
+                max_transactions: None,
+                max_age: Some(RetentionAge::DisputeWindow),
+            },
+            ..Default::default()
+        };
+        let mut client = ClientState::with_config(0, &config);
+        client
+            .apply_operation(Operation::deposit(0, 1, 10).with_timestamp("2022-01-01"))
+            .unwrap();
+        client
+            .apply_operation(Operation::deposit(0, 2, 10).with_timestamp("2022-01-02"))
+            .unwrap();
+        assert_eq!(client.transactions.len(), 2);
+
+        // The first deposit can't be disputed anymore, so it is evicted.
+        client
+            .apply_operation(Operation::deposit(0, 3, 10).with_timestamp("2022-01-02T12:00:00Z"))
+            .unwrap();
+        assert_eq!(client.transactions.len(), 2);
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1)),
+            Err(Rejection::Expired)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 2)),
+            Ok(Transition::Disputed)
+        );
+    }
+
+    // Held transactions due for eviction are set aside instead of going round `tx_order`, which stays oldest first.
+    #[test]
+    fn test_held_transactions_are_held_back() {
+        let config = Config {
+            retention: RetentionPolicy {
+                max_transactions: Some(3),
+                max_age: Some(RetentionAge::Seconds(86400)),
+            },
+            ..Default::default()
+        };
+        let mut client = ClientState::with_config(0, &config);
+        for operation in [
+            Operation::deposit(0, 1, 10).with_timestamp("2022-01-01"),
+            Operation::dispute(0, 1).with_timestamp("2022-01-01"),
+            Operation::deposit(0, 2, 10).with_timestamp("2022-01-01"),
+            Operation::dispute(0, 2).with_timestamp("2022-01-01"),
+            Operation::deposit(0, 3, 10).with_timestamp("2022-01-05"),
+        ] {
+            client.apply_operation(operation).unwrap();
+        }
+        assert_eq!(client.held_back, HashSet::from([1, 2]));
+        assert_eq!(client.tx_order, VecDeque::from([3]));
+
+        // Once settled, the old transaction is evicted before the newer one.
+        client
+            .apply_operation(Operation::resolve(0, 1).with_timestamp("2022-01-05"))
+            .unwrap();
+        assert_eq!(client.held_back, HashSet::from([2]));
+        assert_eq!(client.tx_order, VecDeque::from([3]));
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1)),
+            Err(Rejection::Expired)
+        );
+        assert_eq!(client.transactions.len(), 2);
+    }
+
+    fn disputable_withdrawals() -> ClientState {
+        let config = Config {
+            dispute_policy: DisputePolicy::DepositsAndWithdrawals,
+            ..Default::default()
+        };
+        let mut client = ClientState::with_config(0, &config);
+        client
+            .apply_operation(Operation::deposit(0, 1, 100))
+            .unwrap();
+        client
+            .apply_operation(Operation::withdrawal(0, 2, 100))
+            .unwrap();
+        client
+    }
+
+    #[test]
+    fn test_withdrawals_can_not_be_disputed_by_default() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 100))
+            .unwrap();
+        client
+            .apply_operation(Operation::withdrawal(0, 2, 100))
+            .unwrap();
+        let state = client.clone();
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 2)),
+            Err(Rejection::UnknownTx)
+        );
+        // Operation was not applied!
+        assert_eq!(state, client);
+    }
+
+    #[test]
+    fn test_disputed_withdrawal_is_held() {
+        let mut client = disputable_withdrawals();
+        client.apply_operation(Operation::dispute(0, 2)).unwrap();
+        assert_eq!(client.balance().available, Amount::ZERO);
+        assert_eq!(client.balance().held, Amount::from_units(100));
+        // The held amount can't be withdrawn again.
+        let state = client.clone();
+        assert_eq!(
+            client.apply_operation(Operation::withdrawal(0, 3, 100)),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(state, client);
+    }
+
+    #[test]
+    fn test_resolved_withdrawal_stands() {
+        let mut client = disputable_withdrawals();
+        let state = client.clone();
+        client.apply_operation(Operation::dispute(0, 2)).unwrap();
+        client.apply_operation(Operation::resolve(0, 2)).unwrap();
+        assert_eq!(state.balance().available, client.balance().available);
+        assert_eq!(state.balance().held, client.balance().held);
+    }
+
+    #[test]
+    fn test_charged_back_withdrawal_is_credited() {
+        let mut client = disputable_withdrawals();
+        client.apply_operation(Operation::dispute(0, 2)).unwrap();
+        client.apply_operation(Operation::chargeback(0, 2)).unwrap();
+        assert_eq!(client.balance().available, Amount::from_units(100));
+        assert_eq!(client.balance().held, Amount::ZERO);
+        assert_eq!(client.status, ClientStatus::Frozen);
+    }
+
+    #[test]
+    fn test_duplicate_deposit_keeps_disputed_transaction() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 100))
+            .unwrap();
+        client.apply_operation(Operation::dispute(0, 1)).unwrap();
         let state = client.clone();
-        client.apply_operation(Operation::chargeback(0, 2));
+        assert_eq!(
+            client.apply_operation(Operation::deposit(0, 1, 5)),
+            Err(Rejection::DuplicateTx)
+        );
         // Operation was not applied!
         assert_eq!(state, client);
+        assert_eq!(
+            client.apply_operation(Operation::resolve(0, 1)),
+            Ok(Transition::Resolved)
+        );
+        assert_eq!(client.balance().available, Amount::from_units(100));
+        assert_eq!(client.balance().held, Amount::ZERO);
+        assert_eq!(
+            client.apply_operation(Operation::close(0, 2)),
+            Ok(Transition::Closed)
+        );
+    }
+
+    #[test]
+    fn test_duplicate_withdrawal_keeps_deposit() {
+        let config = Config {
+            dispute_policy: DisputePolicy::DepositsAndWithdrawals,
+            retention: RetentionPolicy {
+                max_transactions: Some(1),
+                max_age: None,
+            },
+            ..Default::default()
+        };
+        let mut client = ClientState::with_config(0, &config);
+        client
+            .apply_operation(Operation::deposit(0, 1, 100))
+            .unwrap();
+        let state = client.clone();
+        assert_eq!(
+            client.apply_operation(Operation::withdrawal(0, 1, 10)),
+            Err(Rejection::DuplicateTx)
+        );
+        assert_eq!(state, client);
+        assert_eq!(client.tx_order, VecDeque::from([1]));
+
+        client.apply_operation(Operation::dispute(0, 1)).unwrap();
+        assert_eq!(client.balance().available, Amount::ZERO);
+        assert_eq!(client.balance().held, Amount::from_units(100));
+
+        // The ids of evicted transactions stay taken.
+        client.apply_operation(Operation::resolve(0, 1)).unwrap();
+        client
+            .apply_operation(Operation::deposit(0, 2, 10))
+            .unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 1)),
+            Err(Rejection::Expired)
+        );
+        assert_eq!(
+            client.apply_operation(Operation::withdrawal(0, 1, 10)),
+            Err(Rejection::DuplicateTx)
+        );
+    }
+
+    #[test]
+    fn test_overflow_is_rejected() {
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, i128::MAX))
+            .unwrap();
+        let before = client.clone();
+        assert_eq!(
+            client.apply_operation(Operation::deposit(0, 2, 1)),
+            Err(Rejection::Overflow)
+        );
+        // The rejected deposit is not stored.
+        assert_eq!(client, before);
+        assert_eq!(
+            client.can_receive_transfer(&Operation::transfer(1, 3, 1, 0)),
+            Err(Rejection::Overflow)
+        );
+        // A rejected operation in another currency doesn't leave an empty balance behind.
+        assert_eq!(
+            client.apply_operation(Operation::withdrawal(0, 4, 1).with_currency("EUR")),
+            Err(Rejection::InsufficientFunds)
+        );
+        assert_eq!(client.balances.len(), 1);
+    }
+
+    #[test]
+    fn test_overflowing_total_is_rejected() {
+        let mut client = disputable_withdrawals();
+        client
+            .apply_operation(Operation::deposit(0, 3, i128::MAX))
+            .unwrap();
+        // A disputed withdrawal is held on top of the available funds.
+        assert_eq!(
+            client.apply_operation(Operation::dispute(0, 2)),
+            Err(Rejection::Overflow)
+        );
+        assert_eq!(client.balance().held, Amount::ZERO);
+
+        // A restored balance isn't checked, so the total of its row can still overflow.
+        client.balances[0].held = Amount::from_units(1);
+        assert!(matches!(client.csv_rows(), Err(Rejection::Overflow)));
+    }
+
+    // A store whose disk is full: stored transactions can be read, new ones are not written.
+    #[derive(Debug, Clone, Default)]
+    struct FullStore(MemoryStore);
+
+    impl TransactionStore for FullStore {
+        fn get(&self, tx_id: u32) -> io::Result<Option<Transaction>> {
+            self.0.get(tx_id)
+        }
+
+        fn insert(&mut self, _: u32, _: Transaction) -> io::Result<()> {
+            Err(io::Error::other("No space left on device"))
+        }
+
+        fn remove(&mut self, tx_id: u32) -> io::Result<()> {
+            self.0.remove(tx_id)
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn transactions(&self) -> Box<dyn Iterator<Item = io::Result<Transaction>> + '_> {
+            self.0.transactions()
+        }
+
+        fn clone_box(&self) -> Box<dyn TransactionStore> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn test_failing_store_rejects_operations() {
+        let mut client =
+            ClientState::with_store(0, &Config::default(), AnyStore::new(FullStore::default()));
+        let state = client.clone();
+        assert_eq!(
+            client.apply_operation(Operation::deposit(0, 1, 10)),
+            Err(Rejection::StorageFailed)
+        );
+        assert_eq!(client, state);
+    }
+
+    // A store that can't list its transactions, to check they are not read one after another.
+    #[derive(Debug, Clone, Default)]
+    struct UnlistedStore(MemoryStore);
+
+    impl TransactionStore for UnlistedStore {
+        fn get(&self, tx_id: u32) -> io::Result<Option<Transaction>> {
+            self.0.get(tx_id)
+        }
+
+        fn insert(&mut self, tx_id: u32, transaction: Transaction) -> io::Result<()> {
+            self.0.insert(tx_id, transaction)
+        }
+
+        fn remove(&mut self, tx_id: u32) -> io::Result<()> {
+            self.0.remove(tx_id)
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn transactions(&self) -> Box<dyn Iterator<Item = io::Result<Transaction>> + '_> {
+            panic!("The transactions are listed")
+        }
+
+        fn clone_box(&self) -> Box<dyn TransactionStore> {
+            Box::new(self.clone())
+        }
+    }
+
+    // Closing an account checks the number of disputed and authorized transactions instead of reading them all.
+    #[test]
+    fn test_close_counts_held_transactions() {
+        let mut client = ClientState::with_store(
+            0,
+            &Config::default(),
+            AnyStore::new(UnlistedStore::default()),
+        );
+        for operation in [
+            Operation::deposit(0, 1, 10),
+            Operation::deposit(0, 4, 10),
+            Operation::dispute(0, 1),
+            Operation::authorize(0, 2, 5),
+        ] {
+            client.apply_operation(operation).unwrap();
+        }
+        assert_eq!(
+            client.apply_operation(Operation::close(0, 3)),
+            Err(Rejection::PendingDisputes)
+        );
+        client.apply_operation(Operation::resolve(0, 1)).unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::close(0, 3)),
+            Err(Rejection::PendingAuthorizations)
+        );
+        client.apply_operation(Operation::void(0, 2)).unwrap();
+        assert_eq!(
+            client.apply_operation(Operation::close(0, 3)),
+            Ok(Transition::Closed)
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        let config = Config {
+            event_sink: Some(SharedEventSink::new(NoSink)),
+            fee_schedule: read_fee_schedule("fees.csv").unwrap(),
+            ..Default::default()
+        };
+        let mut client = ClientState::with_config(0, &config);
+        client
+            .apply_operation(Operation::deposit(0, 1, 1000000))
+            .unwrap();
+        client.apply_operation(Operation::dispute(0, 1)).unwrap();
+        // The deposit is applied to the available funds before the total overflows. The events of rejected
+        // operations are dropped.
+        assert_eq!(
+            client.apply_operation(Operation::deposit(0, 2, i128::MAX)),
+            Err(Rejection::Overflow)
+        );
+        client.apply_operation(Operation::chargeback(0, 1)).unwrap();
+
+        let events: Vec<(EventKind, i128, i128, i128)> = client
+            .drain_events()
+            .map(|event| {
+                (
+                    event.event,
+                    event.amount.units(),
+                    event.available.units(),
+                    event.held.units(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (EventKind::FundsDeposited, 1000000, 1000000, 0),
+                (EventKind::FundsHeld, 1000000, 0, 1000000),
+                (EventKind::ChargedBack, 1000000, 0, 0),
+                (EventKind::FeeCharged, 150000, -150000, 0),
+                (EventKind::AccountFrozen, 0, -150000, 0),
+            ]
+        );
+        assert_eq!(client.drain_events().count(), 0);
+        // Without a sink no events are collected.
+        let mut client = ClientState::new(0);
+        client
+            .apply_operation(Operation::deposit(0, 1, 10))
+            .unwrap();
+        assert_eq!(client.drain_events().count(), 0);
     }
 }
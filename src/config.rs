@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::amount::{Amount, Rounding};
+use crate::event::SharedEventSink;
+use crate::fee::FeeSchedule;
+use crate::rule::RuleEngine;
+use crate::timestamp::parse_duration;
+
+// Decides which kinds of transactions can be disputed.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum DisputePolicy {
+    // Only deposits can be disputed. Withdrawals are not stored, so disputes on them are ignored.
+    #[default]
+    DepositsOnly,
+    // Deposits and withdrawals can be disputed. A disputed withdrawal holds the withdrawn amount
+    // until it is resolved (the withdrawal stands) or charged back (the amount is credited back).
+    DepositsAndWithdrawals,
+}
+
+impl FromStr for DisputePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposits" => Ok(DisputePolicy::DepositsOnly),
+            "deposits-and-withdrawals" => Ok(DisputePolicy::DepositsAndWithdrawals),
+            _ => Err(format!("Unknown dispute policy '{}'.", s)),
+        }
+    }
+}
+
+// Decides what happens to an operation reusing the transaction id of an earlier operation of any client.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum DuplicateTxPolicy {
+    // Transaction ids are not checked across clients.
+    #[default]
+    Off,
+    // The operation is rejected and never reaches the client.
+    Reject,
+    // The operation is applied, but reported as an anomaly (or printed if no anomaly report is written).
+    Flag,
+}
+
+impl FromStr for DuplicateTxPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(DuplicateTxPolicy::Off),
+            "reject" => Ok(DuplicateTxPolicy::Reject),
+            "flag" => Ok(DuplicateTxPolicy::Flag),
+            _ => Err(format!("Unknown duplicate transaction policy '{}'.", s)),
+        }
+    }
+}
+
+// How long transactions are kept after they are no longer disputed. Transactions that are currently disputed
+// or authorized are always kept.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct RetentionPolicy {
+    // Keep at most this many transactions per client, evicting the oldest first.
+    pub max_transactions: Option<usize>,
+    // Evict transactions older than this, measured by the timestamps of the operations.
+    pub max_age: Option<RetentionAge>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RetentionAge {
+    Seconds(u64),
+    // Keep transactions as long as they can be disputed.
+    DisputeWindow,
+}
+
+impl FromStr for RetentionAge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dispute-window" => Ok(RetentionAge::DisputeWindow),
+            _ => parse_duration(s).map(RetentionAge::Seconds),
+        }
+    }
+}
+
+// The last operation applied in an as-of run.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Cutoff {
+    // The operation in this line of the input.
+    Line(u64),
+    // The first operation with this transaction id, e.g. the deposit creating it.
+    Tx(u32),
+}
+
+impl FromStr for Cutoff {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid cutoff '{}', expected line:<n> or tx:<id>.", s);
+        match s.split_once(':') {
+            Some(("line", line)) => line.parse().map(Cutoff::Line).map_err(|_| invalid()),
+            Some(("tx", tx)) => tx.parse().map(Cutoff::Tx).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Cutoff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cutoff::Line(line) => write!(f, "line:{}", line),
+            Cutoff::Tx(tx_id) => write!(f, "tx:{}", tx_id),
+        }
+    }
+}
+
+// Settings that influence how the operations of the input are applied and which reports are written.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Config {
+    // The number of decimals of all amounts (4 if not set) and how digits beyond them are rounded. Both are fixed
+    // per process (see `set_decimals`), so a run asking for other values than an earlier run fails.
+    pub decimals: Option<u32>,
+    pub rounding: Rounding,
+    pub dispute_policy: DisputePolicy,
+    pub duplicate_tx_policy: DuplicateTxPolicy,
+    // How far each client may overdraw the available funds with withdrawals. Defaults to 0.
+    pub credit_limits: HashMap<u16, Amount>,
+    // The fees charged per operation type and client tier.
+    pub fee_schedule: FeeSchedule,
+    // Disputes later than this many seconds after the disputed transaction are rejected.
+    pub dispute_window: Option<u64>,
+    // Rules flagging or blocking suspicious patterns of operations.
+    pub rules: RuleEngine,
+    // Which transactions are evicted from memory. By default all transactions are kept.
+    pub retention: RetentionPolicy,
+    // Stop applying operations after this one, so the output is the state as of that operation.
+    pub as_of: Option<Cutoff>,
+    // Output the history of this client instead of the client states.
+    pub explain_client: Option<u16>,
+    // Keep the transactions in segment files in this directory instead of memory.
+    pub spill_dir: Option<String>,
+    // Continue from the snapshot in this file instead of starting without clients.
+    pub restore_file: Option<String>,
+    // Write a snapshot of the state at the end of the run to this file.
+    pub snapshot_file: Option<String>,
+    // Write the outcome of every operation to this file (csv, or json lines if it ends with `.jsonl`).
+    pub audit_file: Option<String>,
+    // Write anomalies like disputes referencing a transaction of another client to this file.
+    pub anomaly_file: Option<String>,
+    // Write operations matching a rule to this file.
+    pub flag_file: Option<String>,
+    // Publish every change of a client state to this sink.
+    pub event_sink: Option<SharedEventSink>,
+    // Write every change of a client state as double-entry postings to this csv file.
+    pub journal_file: Option<String>,
+}
@@ -0,0 +1,28 @@
+use std::collections::HashMap;
+use std::io;
+
+use csv::{ReaderBuilder, StringRecord, Trim};
+use serde::Deserialize;
+
+use crate::amount::Amount;
+
+#[derive(Debug, PartialEq, Deserialize)]
+struct CreditLimit {
+    client: u16,
+    limit: Amount,
+}
+
+// Read the credit limits per client from a csv file with the columns `client,limit`.
+// Clients without a credit limit can't overdraw their account.
+pub fn read_credit_limits(path: &str) -> io::Result<HashMap<u16, Amount>> {
+    let mut reader = ReaderBuilder::new().trim(Trim::All).from_path(path)?;
+    let mut record = StringRecord::new();
+    let mut limits = HashMap::new();
+
+    while reader.read_record(&mut record)? {
+        let limit: CreditLimit = record.deserialize(None)?;
+        limits.insert(limit.client, limit.limit);
+    }
+
+    Ok(limits)
+}
@@ -0,0 +1,85 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{Error, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const MAX_CURRENCY_LENGTH: usize = 8;
+
+// A currency code like `EUR` or `USDT`. The code is stored inline (padded with zeros) so operations
+// stay `Copy` and don't allocate. The default currency is the empty code, used if the input has no currency.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub struct Currency([u8; MAX_CURRENCY_LENGTH]);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        let length = self
+            .0
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(MAX_CURRENCY_LENGTH);
+        // Only ascii alphanumeric codes can be constructed, so this never fails.
+        std::str::from_utf8(&self.0[..length]).unwrap_or_default()
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() > MAX_CURRENCY_LENGTH || !s.bytes().all(|byte| byte.is_ascii_alphanumeric()) {
+            return Err(format!("Invalid currency '{}'.", s));
+        }
+
+        let mut code = [0; MAX_CURRENCY_LENGTH];
+        code[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Currency(code))
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.as_str().serialize(serializer)
+    }
+}
+
+struct CurrencyVisitor;
+
+impl Visitor<'_> for CurrencyVisitor {
+    type Value = Currency;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a currency code")
+    }
+
+    fn visit_str<E: Error>(self, s: &str) -> Result<Currency, E> {
+        s.parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(CurrencyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_currency() {
+        let currency: Currency = "USDT".parse().unwrap();
+        assert_eq!(currency.as_str(), "USDT");
+        assert_eq!(currency.to_string(), "USDT");
+        assert_eq!("".parse::<Currency>().unwrap(), Currency::default());
+        assert!("TOOLONGCODE".parse::<Currency>().is_err());
+        assert!("E R".parse::<Currency>().is_err());
+    }
+}
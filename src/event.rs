@@ -0,0 +1,166 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+
+// A change of the state of a client. Replaying the events of a client in order gives its funds and status.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub enum EventKind {
+    // `amount` was added to the available funds.
+    FundsDeposited,
+    // `amount` was taken from the available funds.
+    FundsWithdrawn,
+    // A fee of `amount` was taken from the available funds.
+    FeeCharged,
+    // `amount` of the available funds was held, for a disputed deposit or an authorization.
+    FundsHeld,
+    // `amount` of held funds was released to the available funds.
+    FundsReleased,
+    // `amount` of held funds of a disputed deposit was charged back.
+    ChargedBack,
+    // A disputed withdrawal holds the withdrawn `amount`, without touching the available funds.
+    WithdrawalHeld,
+    // `amount` of held funds of a disputed withdrawal was released, the withdrawal stands.
+    WithdrawalReleased,
+    // `amount` of held funds of a disputed withdrawal was charged back to the available funds.
+    WithdrawalChargedBack,
+    // `amount` of the held funds of an authorization was captured.
+    FundsCaptured,
+    // `amount` of a deposit was refunded from the available funds.
+    FundsRefunded,
+    // `amount` was sent from the available funds to another client.
+    TransferredOut,
+    // `amount` was received from another client.
+    TransferredIn,
+    AccountFrozen,
+    AccountUnlocked,
+    AccountClosed,
+}
+
+// An event together with the operation causing it and the resulting funds in the currency of the event.
+// The total funds are the sum of `available` and `held`.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub struct Event {
+    // The input line of the operation.
+    pub line: u64,
+    pub event: EventKind,
+    pub client: u16,
+    pub tx: u32,
+    pub currency: Currency,
+    pub amount: Amount,
+    pub available: Amount,
+    pub held: Amount,
+}
+
+// Receives the events of all clients. The events of each client are published in the order they were applied,
+// events of different clients may be interleaved. The events are published on a blocking thread, see `EventPublisher`,
+// so a sink can write to files or the network directly.
+pub trait EventSink: Debug + Send + Sync {
+    fn publish(&self, events: &[Event]) -> io::Result<()>;
+
+    // Called once after the last events were published.
+    fn flush(&self) -> io::Result<()>;
+}
+
+// Writes the events to a file as json lines.
+#[derive(Debug)]
+pub struct JsonlEventSink {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl JsonlEventSink {
+    pub fn create(path: &str) -> io::Result<JsonlEventSink> {
+        Ok(JsonlEventSink {
+            writer: Mutex::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+}
+
+impl EventSink for JsonlEventSink {
+    fn publish(&self, events: &[Event]) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(|err| err.into_inner());
+        for event in events {
+            serde_json::to_writer(&mut *writer, event)?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.writer
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .flush()
+    }
+}
+
+// An event sink shared by all client futures. Sinks are equal if they are the same sink.
+#[derive(Debug, Clone)]
+pub struct SharedEventSink(pub(crate) Arc<dyn EventSink>);
+
+impl SharedEventSink {
+    pub fn new<S: EventSink + 'static>(sink: S) -> SharedEventSink {
+        SharedEventSink(Arc::new(sink))
+    }
+
+    pub fn publish(&self, events: &[Event]) -> io::Result<()> {
+        self.0.publish(events)
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl PartialEq for SharedEventSink {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+// Publishes the events produced by the per-client futures to a sink. Like the records of a `ReportWriter`, the
+// events are sent in batches over a channel and published by a blocking task, so the client futures never wait
+// for the sink. After an error, the remaining events are dropped and the error fails the run on `finish`.
+pub struct EventPublisher {
+    sender: Sender<Vec<Event>>,
+    handle: JoinHandle<io::Result<()>>,
+}
+
+impl EventPublisher {
+    pub fn spawn(sink: Arc<dyn EventSink>) -> EventPublisher {
+        let (sender, receiver) = channel::<Vec<Event>>();
+        let handle = tokio::task::spawn_blocking(move || {
+            let mut published = Ok(());
+            // Keep receiving after an error, so the senders don't fail.
+            for events in receiver.iter() {
+                if published.is_ok() {
+                    published = sink.publish(&events);
+                }
+            }
+            // The events published before an error are still flushed, but the error comes first.
+            let flushed = sink.flush();
+            published.and(flushed)
+        });
+
+        EventPublisher { sender, handle }
+    }
+
+    pub fn sender(&self) -> Sender<Vec<Event>> {
+        self.sender.clone()
+    }
+
+    // Wait until all events are published and flush the sink. Returns the first error of the sink. All senders must
+    // have been dropped before, otherwise this never finishes.
+    pub async fn finish(self) -> io::Result<()> {
+        drop(self.sender);
+        self.handle.await?
+    }
+}
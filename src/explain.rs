@@ -0,0 +1,80 @@
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::amount::Amount;
+use crate::audit::Outcome;
+use crate::client_state::{Rejection, Transition};
+use crate::currency::Currency;
+use crate::operation::{Operation, OperationType};
+
+// One line of the history of the explained client: an operation touching it and the funds in the currency of the
+// operation afterwards.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Explanation {
+    pub line: u64,
+    #[serde(rename = "type")]
+    pub type_: OperationType,
+    pub tx: u32,
+    pub currency: Currency,
+    pub amount: Amount,
+    // The other client of a transfer.
+    pub counterparty: Option<u16>,
+    pub outcome: Outcome,
+    pub reason: Option<Rejection>,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Option<Amount>,
+}
+
+impl Explanation {
+    pub fn new(
+        line: u64,
+        client: u16,
+        operation: &Operation,
+        result: &Result<Transition, Rejection>,
+        (available, held): (Amount, Amount),
+    ) -> Self {
+        let (outcome, reason) = match result {
+            Ok(_) => (Outcome::Applied, None),
+            Err(rejection) => (Outcome::Rejected, Some(*rejection)),
+        };
+        let counterparty = match operation.to {
+            Some(to) if to != client => Some(to),
+            Some(_) => Some(operation.client),
+            None => None,
+        };
+
+        Explanation {
+            line,
+            type_: operation.type_,
+            tx: operation.tx_id,
+            currency: operation.currency,
+            amount: operation.amount,
+            counterparty,
+            outcome,
+            reason,
+            available,
+            held,
+            total: available.checked_add(held),
+        }
+    }
+}
+
+// The history of the explained client. The futures of a client run one after another, so their explanations
+// are pushed in the order of the input.
+#[derive(Debug, Default)]
+pub struct History(Mutex<Vec<Explanation>>);
+
+impl History {
+    pub fn extend(&self, explanations: Vec<Explanation>) {
+        self.0
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .extend(explanations);
+    }
+
+    pub fn take(&self) -> Vec<Explanation> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|err| err.into_inner()))
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::io;
+
+use csv::{ReaderBuilder, StringRecord, Trim};
+use serde::Deserialize;
+
+use crate::amount::Amount;
+use crate::operation::OperationType;
+
+// A fee of `flat + amount * percentage / 100`. The percentage uses the configured decimals of amounts.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Fee {
+    flat: Amount,
+    percentage: Amount,
+}
+
+impl Fee {
+    // The fee for moving `amount`, or `None` if it overflows. Fractions below the smallest unit are truncated.
+    pub fn for_amount(&self, amount: Amount) -> Option<Amount> {
+        self.flat.checked_add(amount.percentage(self.percentage)?)
+    }
+}
+
+// The fees of a single client.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Fees {
+    pub deposit: Fee,
+    pub withdrawal: Fee,
+    pub chargeback: Fee,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+struct FeeRule {
+    #[serde(rename = "type")]
+    type_: OperationType,
+    // Rules without a tier apply to all clients without a more specific rule.
+    tier: Option<String>,
+    flat: Amount,
+    percentage: Amount,
+}
+
+// The fees per operation type and client tier.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FeeSchedule {
+    rules: Vec<FeeRule>,
+    tiers: HashMap<u16, String>,
+}
+
+impl FeeSchedule {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn tiers(&self) -> &HashMap<u16, String> {
+        &self.tiers
+    }
+
+    pub fn set_tiers(&mut self, tiers: HashMap<u16, String>) {
+        self.tiers = tiers;
+    }
+
+    pub fn fees_for(&self, client: u16) -> Fees {
+        let tier = self.tiers.get(&client);
+        let fee = |type_: OperationType| {
+            let rules = self.rules.iter().filter(|rule| rule.type_ == type_);
+            rules
+                .clone()
+                .find(|rule| rule.tier.is_some() && rule.tier.as_ref() == tier)
+                .or_else(|| rules.clone().find(|rule| rule.tier.is_none()))
+                .map_or_else(Fee::default, |rule| Fee {
+                    flat: rule.flat,
+                    percentage: rule.percentage,
+                })
+        };
+
+        Fees {
+            deposit: fee(OperationType::Deposit),
+            withdrawal: fee(OperationType::Withdrawal),
+            chargeback: fee(OperationType::Chargeback),
+        }
+    }
+}
+
+// Read the fee schedule from a csv file with the columns `type,tier,flat,percentage`.
+pub fn read_fee_schedule(path: &str) -> io::Result<FeeSchedule> {
+    let mut reader = ReaderBuilder::new().trim(Trim::All).from_path(path)?;
+    let mut record = StringRecord::new();
+    let mut rules = Vec::new();
+
+    while reader.read_record(&mut record)? {
+        let rule: FeeRule = record.deserialize(None)?;
+        if !matches!(
+            rule.type_,
+            OperationType::Deposit | OperationType::Withdrawal | OperationType::Chargeback
+        ) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Fees are not supported for {:?}.", rule.type_),
+            ));
+        }
+        rules.push(rule);
+    }
+
+    Ok(FeeSchedule {
+        rules,
+        tiers: HashMap::new(),
+    })
+}
+
+// Read the tier of each client from a csv file with the columns `client,tier`.
+pub fn read_tiers(path: &str) -> io::Result<HashMap<u16, String>> {
+    let mut reader = ReaderBuilder::new().trim(Trim::All).from_path(path)?;
+    let mut record = StringRecord::new();
+    let mut tiers = HashMap::new();
+
+    while reader.read_record(&mut record)? {
+        let (client, tier): (u16, String) = record.deserialize(None)?;
+        tiers.insert(client, tier);
+    }
+
+    Ok(tiers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fees_for_tier() {
+        let mut schedule = read_fee_schedule("fees.csv").unwrap();
+        schedule.set_tiers([(1, "gold".to_string())].into_iter().collect());
+
+        let fees = schedule.fees_for(0);
+        assert_eq!(fees.deposit, Fee::default());
+        // 1.0 + 1%
+        assert_eq!(
+            fees.withdrawal.for_amount(Amount::from_units(1000000)),
+            Some(Amount::from_units(10000 + 10000))
+        );
+        assert_eq!(
+            fees.chargeback.for_amount(Amount::from_units(1000000)),
+            Some(Amount::from_units(150000))
+        );
+
+        // Gold clients have their own withdrawal fee, but use the default chargeback fee.
+        let fees = schedule.fees_for(1);
+        assert_eq!(
+            fees.withdrawal.for_amount(Amount::from_units(1000000)),
+            Some(Amount::from_units(5000))
+        );
+        assert_eq!(
+            fees.chargeback.for_amount(Amount::from_units(1000000)),
+            Some(Amount::from_units(150000))
+        );
+    }
+
+    #[test]
+    fn test_percentage_is_truncated() {
+        let fee = Fee {
+            flat: Amount::from_units(0),
+            percentage: Amount::from_units(15000),
+        };
+        // 1.5% of 0.0001
+        assert_eq!(
+            fee.for_amount(Amount::from_units(1)),
+            Some(Amount::from_units(0))
+        );
+        // 1.5% of 1.0
+        assert_eq!(
+            fee.for_amount(Amount::from_units(10000)),
+            Some(Amount::from_units(150))
+        );
+
+        let fee = Fee {
+            flat: Amount::from_units(1),
+            percentage: Amount::from_units(1000000),
+        };
+        assert_eq!(fee.for_amount(Amount::from_units(i128::MAX)), None);
+    }
+}
@@ -0,0 +1,29 @@
+use serde::Serialize;
+
+use crate::operation::{Operation, OperationType};
+use crate::rule::{RuleAction, RuleMatch};
+
+// One line of the flag report: an operation that matched a rule.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Flag {
+    pub line: u64,
+    pub rule: String,
+    pub action: RuleAction,
+    #[serde(rename = "type")]
+    pub type_: OperationType,
+    pub client: u16,
+    pub tx: u32,
+}
+
+impl Flag {
+    pub fn new(line: u64, operation: &Operation, rule_match: RuleMatch) -> Self {
+        Flag {
+            line,
+            rule: rule_match.rule,
+            action: rule_match.action,
+            type_: operation.type_,
+            client: operation.client,
+            tx: operation.tx_id,
+        }
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::sync::Mutex;
+
+use csv::Writer;
+use serde::Serialize;
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::event::{Event, EventKind, EventSink};
+
+// The accounts of the journal. The client accounts exist once per client, the others are shared by all clients.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JournalAccount {
+    ClientAvailable,
+    ClientHeld,
+    // Funds entering and leaving the engine: deposits, withdrawals, captures and refunds.
+    Settlement,
+    // The fees paid by the clients.
+    House,
+    // Deposits taken back by chargebacks.
+    ChargebackLoss,
+    // Transfers between clients. Sums to zero once both sides of all transfers are applied.
+    Transfers,
+    // The funds of the clients restored from a snapshot.
+    OpeningBalance,
+}
+
+impl JournalAccount {
+    // The account on the other side of the client accounts for an event of `amount`, and its change: positive for
+    // funds leaving the client, negative for funds entering it. Funds moving between the available and held funds
+    // of the client don't change it.
+    fn counterpart(event: EventKind, amount: Amount) -> io::Result<(JournalAccount, Amount)> {
+        let entering = || Amount::ZERO.checked_sub(amount).ok_or_else(overflow);
+        Ok(match event {
+            EventKind::FundsDeposited | EventKind::WithdrawalHeld => {
+                (JournalAccount::Settlement, entering()?)
+            }
+            EventKind::FundsWithdrawn
+            | EventKind::WithdrawalReleased
+            | EventKind::FundsCaptured
+            | EventKind::FundsRefunded => (JournalAccount::Settlement, amount),
+            EventKind::FeeCharged => (JournalAccount::House, amount),
+            EventKind::ChargedBack => (JournalAccount::ChargebackLoss, amount),
+            EventKind::TransferredOut => (JournalAccount::Transfers, amount),
+            EventKind::TransferredIn => (JournalAccount::Transfers, entering()?),
+            EventKind::FundsHeld
+            | EventKind::FundsReleased
+            | EventKind::WithdrawalChargedBack
+            | EventKind::AccountFrozen
+            | EventKind::AccountUnlocked
+            | EventKind::AccountClosed => (JournalAccount::Settlement, Amount::ZERO),
+        })
+    }
+
+    fn is_client_account(self) -> bool {
+        matches!(
+            self,
+            JournalAccount::ClientAvailable | JournalAccount::ClientHeld
+        )
+    }
+}
+
+// One line of the journal. The postings of each event (with the same line, event, client and tx) have equal
+// debits and credits. The balance of an account is its debits minus its credits.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub struct Posting {
+    // The input line of the operation, 0 for opening balances.
+    pub line: u64,
+    pub event: Option<EventKind>,
+    pub client: u16,
+    pub tx: u32,
+    pub currency: Currency,
+    pub account: JournalAccount,
+    pub debit: Option<Amount>,
+    pub credit: Option<Amount>,
+}
+
+fn overflow() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "The journal overflows")
+}
+
+#[derive(Debug)]
+struct JournalState {
+    writer: Writer<BufWriter<File>>,
+    // The available and held funds of each client and currency after the latest event.
+    funds: HashMap<(u16, Currency), (Amount, Amount)>,
+    // The balance of each account. Client accounts are kept per client, the others under client 0.
+    balances: HashMap<(JournalAccount, u16, Currency), Amount>,
+    // The available and held funds of the final client states, see `Journal::close`.
+    closing: HashMap<(u16, Currency), (Amount, Amount)>,
+}
+
+impl JournalState {
+    // Post the change of the client funds to `available` and `held` and the change of the counterpart account.
+    // Fails without posting anything if they don't balance, e.g. because an event has the wrong amount or funds.
+    fn post(
+        &mut self,
+        line: u64,
+        event: Option<EventKind>,
+        (counterpart, counterpart_change): (JournalAccount, Amount),
+        (client, tx, currency): (u16, u32, Currency),
+        (available, held): (Amount, Amount),
+    ) -> io::Result<()> {
+        let previous = self
+            .funds
+            .get(&(client, currency))
+            .copied()
+            .unwrap_or_default();
+        let available_change = available.checked_sub(previous.0).ok_or_else(overflow)?;
+        let held_change = held.checked_sub(previous.1).ok_or_else(overflow)?;
+        let sum = available_change
+            .checked_add(held_change)
+            .and_then(|change| change.checked_add(counterpart_change))
+            .ok_or_else(overflow)?;
+        if sum != Amount::ZERO {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The postings of line {} for client {} and tx {} don't balance by {}",
+                    line, client, tx, sum
+                ),
+            ));
+        }
+        self.funds.insert((client, currency), (available, held));
+
+        for (account, change) in [
+            (JournalAccount::ClientAvailable, available_change),
+            (JournalAccount::ClientHeld, held_change),
+            (counterpart, counterpart_change),
+        ] {
+            if change == Amount::ZERO {
+                continue;
+            }
+            let owner = if account.is_client_account() {
+                client
+            } else {
+                0
+            };
+            let balance = self.balances.entry((account, owner, currency)).or_default();
+            *balance = balance.checked_add(change).ok_or_else(overflow)?;
+
+            let (debit, credit) = if change > Amount::ZERO {
+                (Some(change), None)
+            } else {
+                (
+                    None,
+                    Some(Amount::ZERO.checked_sub(change).ok_or_else(overflow)?),
+                )
+            };
+            self.writer.serialize(Posting {
+                line,
+                event,
+                client,
+                tx,
+                currency,
+                account,
+                debit,
+                credit,
+            })?;
+        }
+        Ok(())
+    }
+
+    // The currencies whose accounts don't sum to zero.
+    fn unbalanced_currencies(&self) -> Vec<Currency> {
+        let mut sums: BTreeMap<Currency, Option<Amount>> = BTreeMap::new();
+        for (&(_, _, currency), &balance) in self.balances.iter() {
+            let sum = sums.entry(currency).or_insert(Some(Amount::ZERO));
+            *sum = sum.and_then(|sum| sum.checked_add(balance));
+        }
+        sums.into_iter()
+            .filter(|(_, sum)| *sum != Some(Amount::ZERO))
+            .map(|(currency, _)| currency)
+            .collect()
+    }
+
+    // The clients and currencies whose client accounts differ from the funds of the final client state.
+    fn unreconciled_funds(&self) -> Vec<(u16, Currency)> {
+        let mut funds: BTreeMap<(u16, Currency), (Amount, Amount)> = BTreeMap::new();
+        for (&(account, client, currency), &balance) in self.balances.iter() {
+            match account {
+                JournalAccount::ClientAvailable => {
+                    funds.entry((client, currency)).or_default().0 = balance
+                }
+                JournalAccount::ClientHeld => {
+                    funds.entry((client, currency)).or_default().1 = balance
+                }
+                _ => {}
+            }
+        }
+        for &key in self.closing.keys() {
+            funds.entry(key).or_default();
+        }
+        funds
+            .into_iter()
+            .filter(|(key, journal)| self.closing.get(key).copied().unwrap_or_default() != *journal)
+            .map(|(key, _)| key)
+            .collect()
+    }
+}
+
+// Writes the events as double-entry postings to a csv file. Every change of the funds of a client is balanced
+// by one of the shared accounts, so all accounts of a currency sum to zero. Each event is checked against the
+// change of the funds when it is posted. The sums and the client accounts matching the final client states are
+// verified on `flush`. The events are published by an `EventPublisher`, so the postings are written on a blocking
+// thread.
+#[derive(Debug)]
+pub struct Journal {
+    state: Mutex<JournalState>,
+}
+
+impl Journal {
+    pub fn create(path: &str) -> io::Result<Journal> {
+        Ok(Journal {
+            state: Mutex::new(JournalState {
+                writer: Writer::from_writer(BufWriter::new(File::create(path)?)),
+                funds: HashMap::new(),
+                balances: HashMap::new(),
+                closing: HashMap::new(),
+            }),
+        })
+    }
+
+    // Post the funds a client starts with, e.g. when it is restored from a snapshot.
+    pub fn open(
+        &self,
+        client: u16,
+        funds: impl Iterator<Item = (Currency, Amount, Amount)>,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        for (currency, available, held) in funds {
+            let opening = available
+                .checked_add(held)
+                .and_then(|total| Amount::ZERO.checked_sub(total))
+                .ok_or_else(overflow)?;
+            state.post(
+                0,
+                None,
+                (JournalAccount::OpeningBalance, opening),
+                (client, 0, currency),
+                (available, held),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Record the funds of the final state of a client. On `flush`, the client accounts of the journal have to
+    // match them, otherwise an event is missing or wrong.
+    pub fn close(&self, client: u16, funds: impl Iterator<Item = (Currency, Amount, Amount)>) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        for (currency, available, held) in funds {
+            state.closing.insert((client, currency), (available, held));
+        }
+    }
+}
+
+impl EventSink for Journal {
+    fn publish(&self, events: &[Event]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        for event in events {
+            state.post(
+                event.line,
+                Some(event.event),
+                JournalAccount::counterpart(event.event, event.amount)?,
+                (event.client, event.tx, event.currency),
+                (event.available, event.held),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.writer.flush()?;
+        let unbalanced = state.unbalanced_currencies();
+        if !unbalanced.is_empty() {
+            let currencies: Vec<String> = unbalanced.iter().map(Currency::to_string).collect();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The journal accounts don't sum to zero in '{}'",
+                    currencies.join("', '")
+                ),
+            ));
+        }
+        let unreconciled = state.unreconciled_funds();
+        if !unreconciled.is_empty() {
+            let funds: Vec<String> = unreconciled
+                .iter()
+                .map(|(client, currency)| format!("client {} in '{}'", client, currency))
+                .collect();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The journal doesn't match the funds of {}",
+                    funds.join(", ")
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event: EventKind, tx: u32, amount: i128, available: i128, held: i128) -> Event {
+        Event {
+            line: tx as u64 + 1,
+            event,
+            client: 1,
+            tx,
+            currency: Currency::default(),
+            amount: Amount::from_units(amount),
+            available: Amount::from_units(available),
+            held: Amount::from_units(held),
+        }
+    }
+
+    fn temp_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(name)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_postings() {
+        let path = temp_file("payment-engine-test-journal.csv");
+        let journal = Journal::create(&path).unwrap();
+        journal
+            .publish(&[
+                event(EventKind::FundsDeposited, 1, 10, 10, 0),
+                event(EventKind::FeeCharged, 1, 1, 9, 0),
+                event(EventKind::FundsWithdrawn, 2, 5, 4, 0),
+                // A disputed withdrawal holds the withdrawn amount, the available funds stay.
+                event(EventKind::WithdrawalHeld, 2, 5, 4, 5),
+                event(EventKind::WithdrawalChargedBack, 2, 5, 9, 0),
+            ])
+            .unwrap();
+        journal.close(
+            1,
+            [(Currency::default(), Amount::from_units(9), Amount::ZERO)].into_iter(),
+        );
+        journal.flush().unwrap();
+
+        let journal = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            journal,
+            "line,event,client,tx,currency,account,debit,credit\n\
+             2,FundsDeposited,1,1,,client-available,0.0010,\n\
+             2,FundsDeposited,1,1,,settlement,,0.0010\n\
+             2,FeeCharged,1,1,,client-available,,0.0001\n\
+             2,FeeCharged,1,1,,house,0.0001,\n\
+             3,FundsWithdrawn,1,2,,client-available,,0.0005\n\
+             3,FundsWithdrawn,1,2,,settlement,0.0005,\n\
+             3,WithdrawalHeld,1,2,,client-held,0.0005,\n\
+             3,WithdrawalHeld,1,2,,settlement,,0.0005\n\
+             3,WithdrawalChargedBack,1,2,,client-available,0.0005,\n\
+             3,WithdrawalChargedBack,1,2,,client-held,,0.0005\n"
+        );
+    }
+
+    #[test]
+    fn test_unbalanced_accounts_are_reported() {
+        let journal = Journal::create(&temp_file("payment-engine-test-unbalanced.csv")).unwrap();
+        let funds = [(Currency::default(), Amount::from_units(5), Amount::ZERO)];
+        journal.open(1, funds.into_iter()).unwrap();
+        journal.close(1, funds.into_iter());
+        journal.flush().unwrap();
+
+        journal.state.lock().unwrap().balances.insert(
+            (JournalAccount::Settlement, 0, Currency::default()),
+            Amount::from_units(1),
+        );
+        assert!(journal.flush().is_err());
+    }
+
+    // An event whose funds don't change by its amount fails, and posts nothing.
+    #[test]
+    fn test_unbalanced_events_are_rejected() {
+        let path = temp_file("payment-engine-test-unbalanced-event.csv");
+        let journal = Journal::create(&path).unwrap();
+        journal
+            .publish(&[event(EventKind::FundsDeposited, 1, 10, 10, 0)])
+            .unwrap();
+        for unbalanced in [
+            event(EventKind::FundsWithdrawn, 2, 5, 6, 0),
+            // A disputed deposit moves the funds within the client, it doesn't hold the withdrawn amount.
+            event(EventKind::FundsHeld, 2, 5, 10, 5),
+        ] {
+            let err = journal.publish(&[unbalanced]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        journal.close(
+            1,
+            [(Currency::default(), Amount::from_units(10), Amount::ZERO)].into_iter(),
+        );
+        journal.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+    }
+
+    // Events that don't add up to the final funds of a client fail the flush, although the accounts sum to zero.
+    #[test]
+    fn test_funds_differing_from_client_states_are_reported() {
+        let journal = Journal::create(&temp_file("payment-engine-test-unreconciled.csv")).unwrap();
+        journal
+            .publish(&[event(EventKind::FundsDeposited, 1, 10, 10, 0)])
+            .unwrap();
+        // Client 2 has funds without any event.
+        journal.close(
+            2,
+            [(Currency::default(), Amount::from_units(3), Amount::ZERO)].into_iter(),
+        );
+        journal.close(
+            1,
+            [(Currency::default(), Amount::from_units(10), Amount::ZERO)].into_iter(),
+        );
+        let err = journal.flush().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The journal doesn't match the funds of client 2 in ''"
+        );
+
+        // The state of client 1 has more funds than its events.
+        journal.close(
+            1,
+            [(Currency::default(), Amount::from_units(12), Amount::ZERO)].into_iter(),
+        );
+        journal.close(
+            2,
+            [(Currency::default(), Amount::ZERO, Amount::ZERO)].into_iter(),
+        );
+        let err = journal.flush().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The journal doesn't match the funds of client 1 in ''"
+        );
+    }
+}
@@ -1,8 +1,8 @@
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io;
-use std::io::{BufReader};
+use std::io::BufReader;
 use std::sync::Arc;
 
 use csv::{ByteRecord, ReaderBuilder, Writer};
@@ -10,37 +10,373 @@ use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
 
 use client_state::ClientState;
-use operation::Operation;
+use operation::{InputOperation, Operation, OperationType};
 use read_num_lines::read_num_lines;
+use transfer::{split_transfer, TransferIn, TransferOut};
 
-use crate::client_state::ClientStateCsv;
+use crate::amount::Amount;
+pub use crate::amount::{set_decimals, set_rounding, Rounding};
+use crate::anomaly::{Anomaly, AnomalyKind};
+use crate::audit::AuditRecord;
+use crate::client_state::{ClientStateCsv, Rejection, Transition};
+pub use crate::config::{
+    Config, Cutoff, DisputePolicy, DuplicateTxPolicy, RetentionAge, RetentionPolicy,
+};
+pub use crate::credit_limit::read_credit_limits;
+use crate::currency::Currency;
+use crate::event::EventPublisher;
+pub use crate::event::{Event, EventKind, EventSink, JsonlEventSink, SharedEventSink};
+use crate::explain::{Explanation, History};
+pub use crate::fee::{read_fee_schedule, read_tiers};
+use crate::flag::Flag;
+use crate::journal::Journal;
+use crate::report::ReportWriter;
+pub use crate::rule::read_rules;
+use crate::snapshot::Snapshot;
+pub use crate::timestamp::parse_duration;
+use crate::transaction_store::{AnyStore, DiskStore, SpillDir};
 
+mod amount;
+mod anomaly;
+mod audit;
 mod client_state;
+mod config;
+mod credit_limit;
+mod currency;
+mod event;
+mod explain;
+mod fee;
+mod flag;
+mod journal;
 mod operation;
 mod read_num_lines;
-mod serialize_fractional;
+mod report;
+mod rule;
+mod snapshot;
+mod timestamp;
+mod transaction;
+mod transaction_store;
+mod transfer;
 
 struct ClientHandles {
     client_work: HashMap<u16, JoinHandle<ClientState>>,
+    config: Arc<Config>,
+    audit: Option<ReportWriter<AuditRecord>>,
+    anomalies: Option<ReportWriter<Anomaly>>,
+    flags: Option<ReportWriter<Flag>>,
+    events: Option<EventPublisher>,
+    journal: Option<Arc<Journal>>,
+    // Posts the events to the journal.
+    journal_events: Option<EventPublisher>,
+    // The segment files of the transactions, if they are kept on disk.
+    spill_dir: Option<Arc<SpillDir>>,
+    // Maps each transaction id seen so far to the client owning it. Only filled if duplicate transaction ids
+    // are checked, anomalies are reported or a snapshot is written, so a restored run can check them.
+    // It is not bounded by the retention policy: the clients keep the ids of evicted transactions as well, and
+    // a duplicate of an evicted transaction is still a duplicate. So it grows by about 16 bytes per transaction.
+    tx_owners: HashMap<u32, u16>,
+    // The history of the explained client, if any.
+    history: Option<Arc<History>>,
+    // The operations of the explained client rejected before they reach the client futures, until they are
+    // passed to its next future.
+    rejected: Vec<(InputOperation, Rejection)>,
+    // Whether the operation of the as-of cutoff was seen. All later operations are dropped.
+    cutoff_reached: bool,
 }
 
 impl ClientHandles {
-    pub fn new() -> ClientHandles {
-        ClientHandles {
-            client_work: HashMap::new(),
+    pub fn new(config: Config) -> io::Result<ClientHandles> {
+        let audit = match &config.audit_file {
+            Some(path) => Some(ReportWriter::create(path)?),
+            None => None,
+        };
+        let anomalies = match &config.anomaly_file {
+            Some(path) => Some(ReportWriter::create(path)?),
+            None => None,
+        };
+        let flags = match &config.flag_file {
+            Some(path) => Some(ReportWriter::create(path)?),
+            None => None,
+        };
+        let events = config
+            .event_sink
+            .as_ref()
+            .map(|sink| EventPublisher::spawn(sink.0.clone()));
+        let journal = match &config.journal_file {
+            Some(path) => Some(Arc::new(Journal::create(path)?)),
+            None => None,
+        };
+        let spill_dir = match &config.spill_dir {
+            Some(directory) => Some(Arc::new(SpillDir::create(directory)?)),
+            None => None,
+        };
+
+        // The restored client states are the prior work of the first batch.
+        let mut client_work = HashMap::new();
+        let mut tx_owners = HashMap::new();
+        if let Some(path) = &config.restore_file {
+            let snapshot = Snapshot::read(path, &config, &|client| new_store(&spill_dir, client))?;
+            for client_state in snapshot.clients {
+                if let Some(journal) = &journal {
+                    journal.open(client_state.client(), client_state.funds())?;
+                }
+                client_work.insert(
+                    client_state.client(),
+                    tokio::spawn(async move { client_state }),
+                );
+            }
+            tx_owners.extend(snapshot.tx_owners);
         }
+
+        // Spawned after the opening balances are posted.
+        let journal_events = journal
+            .clone()
+            .map(|journal| EventPublisher::spawn(journal as Arc<dyn EventSink>));
+        let history = config.explain_client.map(|_| Arc::new(History::default()));
+        Ok(ClientHandles {
+            client_work,
+            config: Arc::new(config),
+            audit,
+            anomalies,
+            flags,
+            events,
+            journal,
+            journal_events,
+            spill_dir,
+            tx_owners,
+            history,
+            rejected: Vec::new(),
+            cutoff_reached: false,
+        })
+    }
+}
+
+// The store of the transactions of a new client state.
+fn new_store(spill_dir: &Option<Arc<SpillDir>>, client: u16) -> AnyStore {
+    match spill_dir {
+        Some(directory) => AnyStore::new(DiskStore::new(directory.clone(), client)),
+        None => AnyStore::default(),
     }
 }
 
 impl ClientHandles {
+    // Keep the rejected operations of the explained client for its history.
+    fn keep_rejected(&mut self, rejected: Vec<(InputOperation, Rejection)>) {
+        let explained = self.config.explain_client;
+        self.rejected.extend(
+            rejected
+                .into_iter()
+                .filter(|(input, _)| Some(input.operation.client) == explained),
+        );
+    }
+
+    fn send_audit_records(&self, audit_records: Vec<AuditRecord>) {
+        if let (Some(audit), false) = (&self.audit, audit_records.is_empty()) {
+            if let Err(err) = audit.sender().send(audit_records) {
+                eprintln!("Failed to send audit records with {}", err);
+            }
+        }
+    }
+
+    // Drop the operations and unparseable lines after the as-of cutoff. This has to be called for each batch in the
+    // order of the input.
+    pub fn apply_cutoff(
+        &mut self,
+        operations: &mut Vec<InputOperation>,
+        unparseable: &mut Vec<u64>,
+    ) {
+        if self.cutoff_reached {
+            operations.clear();
+            unparseable.clear();
+            return;
+        }
+        // The number of operations up to and including the cutoff, if it is in this batch.
+        let keep = match self.config.as_of {
+            // The cutoff line is reached with the first operation or unparseable line on or after it, even if it is
+            // the last line.
+            Some(Cutoff::Line(line))
+                if operations.iter().any(|input| input.line >= line)
+                    || unparseable.iter().any(|&unparseable| unparseable >= line) =>
+            {
+                Some(operations.partition_point(|input| input.line <= line))
+            }
+            Some(Cutoff::Tx(tx_id)) => operations
+                .iter()
+                .position(|input| input.operation.tx_id == tx_id)
+                .map(|position| position + 1),
+            _ => None,
+        };
+        if let Some(keep) = keep {
+            operations.truncate(keep);
+            self.cutoff_reached = true;
+            let last_line = match self.config.as_of {
+                Some(Cutoff::Line(line)) => line,
+                _ => operations.last().map_or(0, |input| input.line),
+            };
+            unparseable.retain(|&line| line <= last_line);
+        }
+    }
+
+    // The lines that could not be parsed are rejected in the audit log.
+    fn reject_unparseable(&self, unparseable: Vec<u64>) {
+        self.send_audit_records(
+            unparseable
+                .into_iter()
+                .map(AuditRecord::unparseable)
+                .collect(),
+        );
+    }
+
+    // Transfers need a receiving client other than the sender.
+    pub fn reject_invalid_transfers(&mut self, operations: &mut Vec<InputOperation>) {
+        let mut audit_records = Vec::new();
+        let mut rejected = Vec::new();
+        operations.retain(|input| {
+            let InputOperation { line, operation } = input;
+            if operation.type_ != OperationType::Transfer
+                || operation.to.is_some_and(|to| to != operation.client)
+            {
+                return true;
+            }
+
+            audit_records.push(AuditRecord::new(
+                *line,
+                operation,
+                &Err(Rejection::InvalidTransfer),
+            ));
+            rejected.push((*input, Rejection::InvalidTransfer));
+            false
+        });
+
+        self.send_audit_records(audit_records);
+        self.keep_rejected(rejected);
+    }
+
+    // Check the transaction ids against all operations of all clients seen so far:
+    // - Operations creating a transaction must use a transaction id that was not used before. Depending on the
+    //   policy, duplicates are removed from `operations` or only reported.
+    // - Operations referencing a transaction of another client are reported as anomalies.
+    // This has to be called for each batch in the order of the input.
+    pub fn check_tx_ids(&mut self, operations: &mut Vec<InputOperation>) {
+        let policy = self.config.duplicate_tx_policy;
+        if policy == DuplicateTxPolicy::Off
+            && self.anomalies.is_none()
+            && self.config.snapshot_file.is_none()
+        {
+            return;
+        }
+
+        let tx_owners = &mut self.tx_owners;
+        let report_anomalies = self.anomalies.is_some();
+        let mut audit_records = Vec::new();
+        let mut rejected = Vec::new();
+        let mut anomalies = Vec::new();
+        operations.retain(|input| {
+            let InputOperation { line, operation } = input;
+            if operation.type_.references_transaction() {
+                match tx_owners.get(&operation.tx_id) {
+                    Some(&owner) if owner != operation.client => {
+                        anomalies.push(Anomaly::new(
+                            *line,
+                            AnomalyKind::CrossClientReference,
+                            operation,
+                            owner,
+                        ));
+                    }
+                    _ => {}
+                }
+                return true;
+            }
+            if !operation.type_.creates_transaction() {
+                return true;
+            }
+
+            let owner = match tx_owners.entry(operation.tx_id) {
+                Entry::Vacant(v) => {
+                    v.insert(operation.client);
+                    return true;
+                }
+                Entry::Occupied(o) => *o.get(),
+            };
+
+            match policy {
+                DuplicateTxPolicy::Reject => {
+                    audit_records.push(AuditRecord::new(
+                        *line,
+                        operation,
+                        &Err(Rejection::DuplicateTx),
+                    ));
+                    rejected.push((*input, Rejection::DuplicateTx));
+                    false
+                }
+                DuplicateTxPolicy::Flag if report_anomalies => {
+                    anomalies.push(Anomaly::new(
+                        *line,
+                        AnomalyKind::DuplicateTx,
+                        operation,
+                        owner,
+                    ));
+                    true
+                }
+                DuplicateTxPolicy::Flag => {
+                    eprintln!(
+                        "Transaction id {} in line {} was already used.",
+                        operation.tx_id, line
+                    );
+                    true
+                }
+                DuplicateTxPolicy::Off => true,
+            }
+        });
+
+        self.send_audit_records(audit_records);
+        self.keep_rejected(rejected);
+        if let (Some(report), false) = (&self.anomalies, anomalies.is_empty()) {
+            if let Err(err) = report.sender().send(anomalies) {
+                eprintln!("Failed to send anomalies with {}", err);
+            }
+        }
+    }
+
     // Wait for the client state futures and write the result as csv to the writer.
-    pub async fn serialize_work<W: io::Write>(&mut self, writer: &mut Writer<W>) {
+    // If fees are charged, the house account collecting them is written after the clients.
+    // If configured, a snapshot of the client states is written as well.
+    pub async fn serialize_work<W: io::Write>(&mut self, writer: &mut Writer<W>) -> io::Result<()> {
+        let mut house: BTreeMap<Currency, Amount> = BTreeMap::new();
+        // The client states are kept for the snapshot.
+        let mut client_states = Vec::new();
         for work in self.client_work.drain() {
             match work.1.await {
                 Ok(result) => {
-                    let csv_data: ClientStateCsv = result.into();
-                    if let Err(err) = writer.serialize(csv_data) {
-                        eprintln!("Failed to serialize to csv with: {:?}", err);
+                    if let Some(journal) = &self.journal {
+                        journal.close(result.client(), result.funds());
+                    }
+                    for (currency, fees) in result.fees_paid() {
+                        let total = house.entry(currency).or_default();
+                        match total.checked_add(fees) {
+                            Some(sum) => *total = sum,
+                            None => eprintln!(
+                                "The fees collected in {} overflow, the house account is incomplete",
+                                currency
+                            ),
+                        }
+                    }
+                    match result.csv_rows() {
+                        Ok(rows) => {
+                            for csv_data in rows {
+                                if let Err(err) = writer.serialize(csv_data) {
+                                    eprintln!("Failed to serialize to csv with: {:?}", err);
+                                }
+                            }
+                        }
+                        Err(err) => eprintln!(
+                            "The total funds of client {} overflow with {:?}",
+                            result.client(),
+                            err
+                        ),
+                    }
+                    if self.config.snapshot_file.is_some() {
+                        client_states.push(result);
                     }
                 }
                 Err(err) => {
@@ -51,17 +387,49 @@ impl ClientHandles {
                 }
             }
         }
+
+        if !self.config.fee_schedule.is_empty() {
+            for (currency, fees) in house {
+                if let Err(err) = writer.serialize(ClientStateCsv::house(currency, fees)) {
+                    eprintln!("Failed to serialize to csv with: {:?}", err);
+                }
+            }
+        }
+
+        match &self.config.snapshot_file {
+            // A client whose transactions can't be read fails the snapshot, not the output. Reading the
+            // transactions and writing the file blocks.
+            Some(path) => {
+                let path = path.clone();
+                let tx_owners = std::mem::take(&mut self.tx_owners);
+                tokio::task::spawn_blocking(move || {
+                    Snapshot::write(&path, &client_states, &tx_owners)
+                })
+                .await
+                .map_err(io::Error::other)?
+            }
+            None => Ok(()),
+        }
     }
 }
 
-// Parse the data as csv into a vector of operations.
-fn parse_csv<R: io::Read>(read: R, chunk_size: usize) -> Vec<Operation> {
-    let mut operations: Vec<Operation> = Vec::with_capacity(chunk_size);
+// Parse the data as csv into a vector of operations. `first_line` is the line number of the first line of `read`
+// within the input file.
+// Returns the operations and the lines that are not valid operations.
+fn parse_csv<R: io::Read>(
+    read: R,
+    chunk_size: usize,
+    first_line: u64,
+) -> (Vec<InputOperation>, Vec<u64>) {
+    let mut operations: Vec<InputOperation> = Vec::with_capacity(chunk_size);
+    let mut unparseable = Vec::new();
     let mut record = ByteRecord::new();
     let mut trimmed = ByteRecord::new();
 
     let mut reader = ReaderBuilder::new()
         .has_headers(false)
+        // Optional columns may be left out.
+        .flexible(true)
         .buffer_capacity(512)
         .from_reader(read);
 
@@ -71,12 +439,17 @@ fn parse_csv<R: io::Read>(read: R, chunk_size: usize) -> Vec<Operation> {
                 // This is a custom function (see `csv.patch`) because `ByteRecord::trim` will
                 // allocate memory. This version will re-use the memory similar to `ByteRecord::read_byte_record`.
                 record.trim_noalloc(&mut trimmed);
-                match trimmed.deserialize(None) {
+                let line = record.position().map_or(1, |position| position.line());
+                match trimmed.deserialize::<Operation>(None) {
                     Ok(operation) => {
-                        operations.push(operation);
+                        operations.push(InputOperation {
+                            line: first_line + line - 1,
+                            operation,
+                        });
                     }
                     Err(err) => {
                         eprintln!("Failed to deserialize csv record with error {}.", err);
+                        unparseable.push(first_line + line - 1);
                     }
                 };
             }
@@ -86,49 +459,266 @@ fn parse_csv<R: io::Read>(read: R, chunk_size: usize) -> Vec<Operation> {
             }
             Err(err) => {
                 eprintln!("Failed to read csv record with error {}.", err);
+                let line = err
+                    .position()
+                    .map_or(reader.position().line(), |position| position.line());
+                unparseable.push(first_line + line - 1);
             }
         }
     }
 
-    operations
+    (operations, unparseable)
 }
 
 const EXPECTED_OPERATIONS_PER_CLIENT: usize = 1024 * 100;
 
-fn split_into_client_operations(operations: &mut Vec<Operation>) -> HashMap<u16, Vec<Operation>> {
-    let mut client_operations: HashMap<u16, Vec<Operation>> = HashMap::new();
-    operations.drain(..).for_each(|operation| {
-        let client_ops = client_operations.entry(operation.client);
-        let ops = match client_ops {
-            Entry::Occupied(ops) => ops.into_mut(),
-            Entry::Vacant(v) => v.insert(Vec::with_capacity(EXPECTED_OPERATIONS_PER_CLIENT)),
-        };
-        ops.push(operation);
+// An operation applied to a single client. A transfer touches two clients, so it is split into two operations.
+enum ClientOperation {
+    Single(InputOperation),
+    TransferOut(TransferOut),
+    TransferIn(TransferIn),
+    // An operation of the explained client rejected before it reached the client.
+    Rejected(InputOperation, Rejection),
+}
+
+impl ClientOperation {
+    fn line(&self) -> u64 {
+        match self {
+            ClientOperation::Single(input) | ClientOperation::Rejected(input, _) => input.line,
+            ClientOperation::TransferOut(transfer) => transfer.input.line,
+            ClientOperation::TransferIn(transfer) => transfer.input.line,
+        }
+    }
+}
+
+fn push_client_operation(
+    client_operations: &mut HashMap<u16, Vec<ClientOperation>>,
+    client: u16,
+    operation: ClientOperation,
+) {
+    let ops = match client_operations.entry(client) {
+        Entry::Occupied(ops) => ops.into_mut(),
+        Entry::Vacant(v) => v.insert(Vec::with_capacity(EXPECTED_OPERATIONS_PER_CLIENT)),
+    };
+    ops.push(operation);
+}
+
+// Transfers must have been validated before (see `ClientHandles::reject_invalid_transfers`).
+fn split_into_client_operations(
+    operations: &mut Vec<InputOperation>,
+) -> HashMap<u16, Vec<ClientOperation>> {
+    let mut client_operations: HashMap<u16, Vec<ClientOperation>> = HashMap::new();
+    operations.drain(..).for_each(|input| {
+        let client = input.operation.client;
+        match (input.operation.type_, input.operation.to) {
+            (OperationType::Transfer, Some(to)) => {
+                let (transfer_out, transfer_in) = split_transfer(input);
+                push_client_operation(
+                    &mut client_operations,
+                    client,
+                    ClientOperation::TransferOut(transfer_out),
+                );
+                push_client_operation(
+                    &mut client_operations,
+                    to,
+                    ClientOperation::TransferIn(transfer_in),
+                );
+            }
+            _ => push_client_operation(
+                &mut client_operations,
+                client,
+                ClientOperation::Single(input),
+            ),
+        }
     });
 
     client_operations
 }
 
+// The outcomes of the operations of one client in a batch, collected for the reports.
+#[derive(Default)]
+struct ClientBatch {
+    client: u16,
+    // Whether the operations are audited or explained at all.
+    audited: bool,
+    explained: bool,
+    audit_records: Vec<AuditRecord>,
+    flags: Vec<Flag>,
+    events: Vec<Event>,
+    explanations: Vec<Explanation>,
+}
+
+impl ClientBatch {
+    // Apply operations that don't wait for another client, see `ClientState::offload`.
+    fn apply(&mut self, client_state: &mut ClientState, operations: Vec<ClientOperation>) {
+        for operation in operations {
+            // Rejected operations leave the client state untouched.
+            match operation {
+                ClientOperation::Single(input) => {
+                    let result = client_state.apply_operation(input.operation);
+                    self.record(client_state, input, result, true);
+                }
+                // Already audited when it was rejected.
+                ClientOperation::Rejected(input, rejection) => {
+                    self.record(client_state, input, Err(rejection), false);
+                }
+                ClientOperation::TransferOut(_) | ClientOperation::TransferIn(_) => {
+                    unreachable!("Transfers wait for the other client")
+                }
+            }
+        }
+    }
+
+    // Collect the outcome of an operation just applied to `client_state`.
+    fn record(
+        &mut self,
+        client_state: &mut ClientState,
+        input: InputOperation,
+        result: Result<Transition, Rejection>,
+        audited: bool,
+    ) {
+        if self.explained {
+            self.explanations.push(Explanation::new(
+                input.line,
+                self.client,
+                &input.operation,
+                &result,
+                client_state.funds_in(input.operation.currency),
+            ));
+        }
+        if self.audited && audited {
+            self.audit_records
+                .push(AuditRecord::new(input.line, &input.operation, &result));
+        }
+        for rule_match in client_state.drain_rule_matches() {
+            self.flags
+                .push(Flag::new(input.line, &input.operation, rule_match));
+        }
+        self.events
+            .extend(client_state.drain_events().map(|event| Event {
+                line: input.line,
+                ..event
+            }));
+    }
+}
+
 async fn spawn_for_each_client(
     world: Arc<Mutex<ClientHandles>>,
-    client_operations: &mut HashMap<u16, Vec<Operation>>,
+    client_operations: &mut HashMap<u16, Vec<ClientOperation>>,
 ) {
     let mut world = world.lock().await;
 
     for (client, mut operations) in client_operations.drain() {
         let prior_work = world.client_work.remove(&client);
+        let config = world.config.clone();
+        let audit = world.audit.as_ref().map(|audit| audit.sender());
+        let flag_report = world.flags.as_ref().map(|flags| flags.sender());
+        let spill_dir = world.spill_dir.clone();
+        let event_sink = world.events.as_ref().map(|events| events.sender());
+        let journal = world
+            .journal_events
+            .as_ref()
+            .map(|journal| journal.sender());
+        let history = world
+            .history
+            .clone()
+            .filter(|_| config.explain_client == Some(client));
         let future = tokio::spawn(async move {
             // Wait for the client state computed based on a prior batch.
             let mut client_state = if let Some(work) = prior_work {
                 work.await.expect("Failed to compute client state")
             } else {
                 // or initialize a new one if this is the first batch for this client state.
-                ClientState::new(client)
+                ClientState::with_store(client, &config, new_store(&spill_dir, client))
+            };
+
+            let mut batch = ClientBatch {
+                client,
+                audited: audit.is_some(),
+                explained: history.is_some(),
+                ..Default::default()
             };
+            let mut operations = operations.drain(..).peekable();
+            loop {
+                // The operations up to the next transfer are applied in one go, so a store doing blocking I/O
+                // only leaves the worker threads once for all of them.
+                let mut local = Vec::new();
+                while let Some(operation) = operations.next_if(|operation| {
+                    matches!(
+                        operation,
+                        ClientOperation::Single(_) | ClientOperation::Rejected(..)
+                    )
+                }) {
+                    local.push(operation);
+                }
+                if !local.is_empty() {
+                    (client_state, batch) = client_state
+                        .offload(move |state| {
+                            batch.apply(state, local);
+                            batch
+                        })
+                        .await;
+                }
+
+                // Transfers wait for the other client, so they are applied one by one.
+                let (input, result, audited);
+                match operations.next() {
+                    Some(ClientOperation::TransferOut(transfer)) => {
+                        input = transfer.input;
+                        (client_state, result) = transfer.apply(client_state).await;
+                        audited = true;
+                    }
+                    // The outcome of a transfer is reported by the sender.
+                    Some(ClientOperation::TransferIn(transfer)) => {
+                        input = transfer.input;
+                        (client_state, result) = transfer.apply(client_state).await;
+                        audited = false;
+                    }
+                    Some(_) => unreachable!("Local operations are applied above"),
+                    None => break,
+                }
+                batch.record(&mut client_state, input, result, audited);
+            }
+            let ClientBatch {
+                audit_records,
+                flags,
+                events,
+                explanations,
+                ..
+            } = batch;
 
-            operations.drain(..).for_each(|operation| {
-                client_state.apply_operation(operation);
-            });
+            if let Some(history) = history {
+                history.extend(explanations);
+            }
+            if let Some(journal) = journal {
+                if let Err(err) = journal.send(events.clone()) {
+                    eprintln!("Failed to send events to the journal with {}", err);
+                }
+            }
+            if let Some(sink) = event_sink {
+                if let Err(err) = sink.send(events) {
+                    eprintln!("Failed to send events with {}", err);
+                }
+            }
+
+            if let Some(audit) = audit {
+                if let Err(err) = audit.send(audit_records) {
+                    eprintln!("Failed to send audit records with {}", err);
+                }
+            }
+            match flag_report {
+                Some(report) => {
+                    if let Err(err) = report.send(flags) {
+                        eprintln!("Failed to send flags with {}", err);
+                    }
+                }
+                None => flags.iter().for_each(|flag| {
+                    eprintln!(
+                        "Operation in line {} matched rule {} ({:?}).",
+                        flag.line, flag.rule, flag.action
+                    )
+                }),
+            }
 
             client_state
         });
@@ -137,9 +727,27 @@ async fn spawn_for_each_client(
     }
 }
 
-// Split the incoming operations into operations per-client and spawn the futures returning the client state.
-async fn perform_work(operations: &mut Vec<Operation>, world: Arc<Mutex<ClientHandles>>) {
+// Drop the operations after the cutoff, audit the unparseable lines, check the transfers and transaction ids, split the incoming operations into operations per-client and spawn the futures returning the client state.
+async fn perform_work(
+    operations: &mut Vec<InputOperation>,
+    mut unparseable: Vec<u64>,
+    world: Arc<Mutex<ClientHandles>>,
+) {
+    let rejected = {
+        let mut world = world.lock().await;
+        world.apply_cutoff(operations, &mut unparseable);
+        world.reject_unparseable(unparseable);
+        world.reject_invalid_transfers(operations);
+        world.check_tx_ids(operations);
+        std::mem::take(&mut world.rejected)
+    };
     let mut client_operations = split_into_client_operations(operations);
+    // The rejected operations of the explained client go to its future, in the order of the input.
+    for (input, rejection) in rejected {
+        let operations = client_operations.entry(input.operation.client).or_default();
+        let position = operations.partition_point(|operation| operation.line() < input.line);
+        operations.insert(position, ClientOperation::Rejected(input, rejection));
+    }
 
     spawn_for_each_client(world, &mut client_operations).await;
 }
@@ -149,9 +757,10 @@ async fn parse_and_compute(
     world: Arc<Mutex<ClientHandles>>,
     data: Vec<u8>,
     chunk_size: usize,
+    first_line: u64,
     last_handle: Option<JoinHandle<()>>,
 ) {
-    let mut operations = parse_csv(&data[..], chunk_size);
+    let (mut operations, unparseable) = parse_csv(&data[..], chunk_size, first_line);
 
     if let Some(prio_task) = last_handle {
         if let Err(err) = prio_task.await {
@@ -159,7 +768,7 @@ async fn parse_and_compute(
         }
     }
 
-    perform_work(&mut operations, world).await;
+    perform_work(&mut operations, unparseable, world).await;
 }
 
 // Read the csv file in `filename`, process the operations and write the resulting client state into the passed `writer`.
@@ -167,7 +776,11 @@ pub async fn read_file_and_output_to_writer<W: io::Write>(
     filename: &str,
     writer: &mut Writer<W>,
     chunk_size: Option<usize>,
+    config: Config,
 ) -> io::Result<()> {
+    // Amounts are parsed with the decimals and rounding of the process, which a run can't change.
+    amount::configure(config.decimals, config.rounding)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
     let file = File::open(filename)?;
     // We split the incoming csv data into multiple parts, each having `lines_per_batch` lines.
     let lines_per_batch = chunk_size.unwrap_or(1024 * 1024 * 10);
@@ -185,10 +798,27 @@ pub async fn read_file_and_output_to_writer<W: io::Write>(
     // before spawning the futures itself.
     let mut last_task_handle: Option<JoinHandle<()>> = None;
 
+    let as_of = config.as_of;
     // Stores the futures that will return the client state for each client.
-    let client_handles = Arc::new(Mutex::new(ClientHandles::new()));
+    // Restoring the client states may write spilled transactions to disk, which blocks.
+    let client_handles = tokio::task::spawn_blocking(move || ClientHandles::new(config))
+        .await
+        .map_err(io::Error::other)??;
+    let client_handles = Arc::new(Mutex::new(client_handles));
+
+    // The header is line 1, the first batch starts at line 2.
+    let mut first_line = 2;
 
     loop {
+        // Batches after the as-of cutoff would be dropped anyway.
+        let after_cutoff = match as_of {
+            Some(Cutoff::Line(line)) => first_line > line,
+            _ => false,
+        };
+        if after_cutoff || client_handles.lock().await.cutoff_reached {
+            break;
+        }
+
         let mut data = Vec::with_capacity(lines_per_batch * 50);
         match read_num_lines(&mut reader, lines_per_batch, &mut data) {
             Ok(read) => {
@@ -206,8 +836,10 @@ pub async fn read_file_and_output_to_writer<W: io::Write>(
             client_handles.clone(),
             data,
             lines_per_batch,
+            first_line,
             last_task_handle.take(),
-        )))
+        )));
+        first_line += lines_per_batch as u64;
     }
 
     if let Some(handle) = last_task_handle.take() {
@@ -220,10 +852,57 @@ pub async fn read_file_and_output_to_writer<W: io::Write>(
     }
 
     let mut world = client_handles.lock().await;
+    if let (Some(cutoff), false) = (world.config.as_of, world.cutoff_reached) {
+        eprintln!(
+            "The as-of cutoff {} was not found, the output is the final state",
+            cutoff
+        );
+    }
 
-    world.serialize_work(writer).await;
+    let written = match world.history.clone() {
+        // Only the history of the explained client is written. The client states are still computed for the
+        // snapshot and the reports.
+        Some(history) => {
+            let serialized = world
+                .serialize_work(&mut Writer::from_writer(io::sink()))
+                .await;
+            let history = history.take();
+            if history.is_empty() {
+                eprintln!("No operation touched the explained client");
+            }
+            serialized.and_then(|()| {
+                history
+                    .into_iter()
+                    .try_for_each(|explanation| writer.serialize(explanation))
+                    .map_err(io::Error::from)
+            })
+        }
+        None => world.serialize_work(writer).await,
+    };
 
-    Ok(())
+    // All client futures finished, so the publishers and report writers received all events and records. Every one
+    // of them is finished even if an earlier one failed, so the reports of a failed run are complete. The first
+    // error is returned.
+    let mut results = vec![written];
+    if let Some(events) = world.events.take() {
+        results.push(events.finish().await);
+    }
+    // Fails if an event doesn't balance, or if the journal accounts don't sum to zero or don't match the funds of the
+    // client states.
+    if let Some(journal) = world.journal_events.take() {
+        results.push(journal.finish().await);
+    }
+    if let Some(audit) = world.audit.take() {
+        results.push(audit.finish().await);
+    }
+    if let Some(anomalies) = world.anomalies.take() {
+        results.push(anomalies.finish().await);
+    }
+    if let Some(flags) = world.flags.take() {
+        results.push(flags.finish().await);
+    }
+
+    results.into_iter().collect()
 }
 
 #[cfg(test)]
@@ -233,10 +912,14 @@ mod tests {
     use super::*;
 
     async fn run_payment_engine(filename: &str, results: &[&str]) {
+        run_payment_engine_with_config(filename, Config::default(), results).await
+    }
+
+    async fn run_payment_engine_with_config(filename: &str, config: Config, results: &[&str]) {
         let mut buf = BufWriter::new(Vec::new());
         {
             let mut writer = Writer::from_writer(&mut buf);
-            read_file_and_output_to_writer(filename, &mut writer, Some(1))
+            read_file_and_output_to_writer(filename, &mut writer, Some(1), config)
                 .await
                 .expect("Failed to compute file");
             writer.flush().unwrap();
@@ -254,7 +937,7 @@ mod tests {
     async fn test_lock_account() {
         run_payment_engine(
             "lock-account.csv",
-            &["client,available,held,total,locked\n0,-55.5000,0.0,-55.5000,true\n"],
+            &["client,available,held,total,locked,in_debt,currency\n0,-55.5000,0.0,-55.5000,true,true,\n"],
         )
         .await;
     }
@@ -263,20 +946,89 @@ mod tests {
     async fn test_resolved_dispute() {
         run_payment_engine(
             "resolved-dispute.csv",
-            &["client,available,held,total,locked\n0,44.5000,0.0,44.5000,false\n"],
+            &["client,available,held,total,locked,in_debt,currency\n0,44.5000,0.0,44.5000,false,false,\n"],
         )
         .await;
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_as_of() {
+        for (cutoff, row) in [
+            (Cutoff::Tx(1), "0,100.0,0.0,100.0,false,false,\n"),
+            (Cutoff::Tx(2), "0,44.5000,0.0,44.5000,false,false,\n"),
+            (Cutoff::Line(4), "0,-55.5000,100.0,44.5000,false,true,\n"),
+            // The last line
+            (Cutoff::Line(5), "0,44.5000,0.0,44.5000,false,false,\n"),
+            // Beyond the input
+            (Cutoff::Line(100), "0,44.5000,0.0,44.5000,false,false,\n"),
+        ] {
+            run_payment_engine_with_config(
+                "resolved-dispute.csv",
+                Config {
+                    as_of: Some(cutoff),
+                    ..Default::default()
+                },
+                &[row],
+            )
+            .await;
+        }
+    }
+
+    // A cutoff on the last line of the input is found, a cutoff beyond it is not.
+    #[test]
+    fn test_cutoff_on_last_line() {
+        let operations: Vec<InputOperation> = (1..=3)
+            .map(|tx_id| InputOperation {
+                line: tx_id as u64 + 1,
+                operation: Operation::deposit(0, tx_id, 10),
+            })
+            .collect();
+        for (line, reached) in [(3, true), (4, true), (5, false)] {
+            let mut handles = ClientHandles::new(Config {
+                as_of: Some(Cutoff::Line(line)),
+                ..Default::default()
+            })
+            .unwrap();
+            let mut batch = operations.clone();
+            handles.apply_cutoff(&mut batch, &mut Vec::new());
+            assert_eq!(handles.cutoff_reached, reached, "{}", line);
+            assert_eq!(batch.len(), (line as usize - 1).min(3), "{}", line);
+        }
+    }
+
+    // A cutoff on an unparseable last line is found as well.
+    #[test]
+    fn test_cutoff_on_unparseable_line() {
+        let operations: Vec<InputOperation> = (1..=2)
+            .map(|tx_id| InputOperation {
+                line: tx_id as u64 + 1,
+                operation: Operation::deposit(0, tx_id, 10),
+            })
+            .collect();
+        for (line, reached) in [(4, true), (5, false)] {
+            let mut handles = ClientHandles::new(Config {
+                as_of: Some(Cutoff::Line(line)),
+                ..Default::default()
+            })
+            .unwrap();
+            let mut batch = operations.clone();
+            let mut unparseable = vec![4];
+            handles.apply_cutoff(&mut batch, &mut unparseable);
+            assert_eq!(handles.cutoff_reached, reached, "{}", line);
+            assert_eq!(batch.len(), 2, "{}", line);
+            assert_eq!(unparseable, vec![4], "{}", line);
+        }
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn test_three_clients() {
         run_payment_engine(
             "three-clients.csv",
             &[
-                "client,available,held,total,locked",
-                "2,-1.0,98.0,97.0,false",
-                "0,99.0,0.0,99.0,false",
-                "1,-1.0,99.0,98.0,false",
+                "client,available,held,total,locked,in_debt,currency",
+                "2,-1.0,98.0,97.0,false,true",
+                "0,99.0,0.0,99.0,false,false",
+                "1,-1.0,99.0,98.0,false,true",
             ],
         )
         .await;
@@ -287,10 +1039,562 @@ mod tests {
         run_payment_engine(
             "with-whitespace.csv",
             &[
-                "client,available,held,total,locked",
-                "0,100.0,0.0,100.0,false",
+                "client,available,held,total,locked,in_debt,currency",
+                "0,100.0,0.0,100.0,false,false",
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_charged_back_withdrawal() {
+        // The journal fails the run if the postings of the withdrawal dispute don't balance.
+        let journal = temp_file("payment-engine-test-withdrawal-journal.csv");
+        run_payment_engine_with_config(
+            "charged-back-withdrawal.csv",
+            Config {
+                dispute_policy: DisputePolicy::DepositsAndWithdrawals,
+                journal_file: Some(journal.clone()),
+                ..Default::default()
+            },
+            &["client,available,held,total,locked,in_debt,currency\n0,100.0,0.0,100.0,true,false,\n"],
+        )
+        .await;
+
+        let postings = std::fs::read_to_string(&journal).unwrap();
+        std::fs::remove_file(&journal).unwrap();
+        assert!(postings.contains("4,WithdrawalHeld,0,2,,settlement,,55.5000\n"));
+        assert!(postings.contains("5,WithdrawalChargedBack,0,2,,client-available,55.5000,\n"));
+    }
+
+    fn temp_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(name)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_audit_log() {
+        let audit_file = temp_file("payment-engine-test-audit.csv");
+        run_payment_engine_with_config(
+            "rejected-operations.csv",
+            Config {
+                audit_file: Some(audit_file.clone()),
+                ..Default::default()
+            },
+            &["0,0.0,0.0,0.0,true,false"],
+        )
+        .await;
+
+        let audit = std::fs::read_to_string(&audit_file).unwrap();
+        assert!(audit.starts_with("line,type,client,tx,outcome,reason\n"));
+        for line in [
+            "2,deposit,0,1,applied,\n",
+            "3,withdrawal,0,2,rejected,InsufficientFunds\n",
+            "4,dispute,0,5,rejected,UnknownTx\n",
+            "5,dispute,0,1,applied,\n",
+            "6,dispute,0,1,rejected,AlreadyDisputed\n",
+            "7,chargeback,0,1,applied,\n",
+            "8,deposit,0,3,rejected,AccountFrozen\n",
+            "9,resolve,1,1,rejected,UnknownTx\n",
+            "10,,,,rejected,Unparseable\n",
+        ] {
+            assert!(audit.contains(line), "{} missing in {}", line, audit);
+        }
+        assert_eq!(audit.lines().count(), 10);
+    }
+
+    // Fails every publish.
+    #[derive(Debug)]
+    struct FailingSink;
+
+    impl EventSink for FailingSink {
+        fn publish(&self, _: &[Event]) -> io::Result<()> {
+            Err(io::Error::other("The sink is gone"))
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // A failing event sink fails the run, but the audit log is still written completely.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_failing_sink_keeps_audit_log() {
+        let audit_file = temp_file("payment-engine-test-audit-failing-sink.csv");
+        let mut writer = Writer::from_writer(Vec::new());
+        let result = read_file_and_output_to_writer(
+            "rejected-operations.csv",
+            &mut writer,
+            Some(1),
+            Config {
+                audit_file: Some(audit_file.clone()),
+                event_sink: Some(SharedEventSink::new(FailingSink)),
+                ..Default::default()
+            },
+        )
+        .await;
+        let audit = std::fs::read_to_string(&audit_file).unwrap();
+        std::fs::remove_file(&audit_file).unwrap();
+
+        assert_eq!(result.unwrap_err().to_string(), "The sink is gone");
+        assert_eq!(audit.lines().count(), 10);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_audit_log_as_json_lines() {
+        let audit_file = temp_file("payment-engine-test-audit.jsonl");
+        run_payment_engine_with_config(
+            "rejected-operations.csv",
+            Config {
+                audit_file: Some(audit_file.clone()),
+                ..Default::default()
+            },
+            &[],
+        )
+        .await;
+
+        let audit = std::fs::read_to_string(&audit_file).unwrap();
+        assert!(audit.contains(
+            r#"{"line":3,"type":"withdrawal","client":0,"tx":2,"outcome":"rejected","reason":"InsufficientFunds"}"#
+        ));
+        assert!(audit.contains(
+            r#"{"line":10,"type":null,"client":null,"tx":null,"outcome":"rejected","reason":"Unparseable"}"#
+        ));
+        assert_eq!(audit.lines().count(), 9);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_duplicate_tx_ids_of_other_clients_are_applied_by_default() {
+        // The repeated deposit of client 0 is rejected by the client itself.
+        run_payment_engine(
+            "duplicate-tx.csv",
+            &["0,10.0,0.0,10.0,false,false", "1,5.0,0.0,5.0,false,false"],
+        )
+        .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reject_duplicate_tx_ids() {
+        let audit_file = temp_file("payment-engine-test-duplicates.csv");
+        run_payment_engine_with_config(
+            "duplicate-tx.csv",
+            Config {
+                duplicate_tx_policy: DuplicateTxPolicy::Reject,
+                audit_file: Some(audit_file.clone()),
+                ..Default::default()
+            },
+            &["0,10.0,0.0,10.0,false,false", "1,0.0,0.0,0.0,false,false"],
+        )
+        .await;
+
+        let audit = std::fs::read_to_string(&audit_file).unwrap();
+        assert!(audit.contains("3,deposit,1,1,rejected,DuplicateTx\n"));
+        assert!(audit.contains("4,deposit,0,1,rejected,DuplicateTx\n"));
+        assert!(audit.contains("5,withdrawal,1,3,applied,\n"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_anomaly_report() {
+        let anomaly_file = temp_file("payment-engine-test-anomalies.csv");
+        run_payment_engine_with_config(
+            "cross-client-dispute.csv",
+            Config {
+                duplicate_tx_policy: DuplicateTxPolicy::Flag,
+                anomaly_file: Some(anomaly_file.clone()),
+                ..Default::default()
+            },
+            &["0,15.0,0.0,15.0,false,false", "1,5.0,0.0,5.0,false,false"],
+        )
+        .await;
+
+        let anomalies = std::fs::read_to_string(&anomaly_file).unwrap();
+        assert!(anomalies.starts_with("line,kind,type,client,tx,owner\n"));
+        for line in [
+            "3,CrossClientReference,dispute,1,1,0\n",
+            "4,CrossClientReference,chargeback,1,1,0\n",
+            "5,DuplicateTx,deposit,1,1,0\n",
+        ] {
+            assert!(
+                anomalies.contains(line),
+                "{} missing in {}",
+                line,
+                anomalies
+            );
+        }
+        assert_eq!(anomalies.lines().count(), 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_credit_limits() {
+        run_payment_engine_with_config(
+            "overdraft.csv",
+            Config {
+                credit_limits: read_credit_limits("credit-limits.csv").unwrap(),
+                ..Default::default()
+            },
+            &[
+                "client,available,held,total,locked,in_debt,currency",
+                "0,-5.0,0.0,-5.0,false,false",
+                "1,-80.0,100.0,20.0,false,true",
+                "2,1.0,0.0,1.0,false,false",
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_currencies() {
+        run_payment_engine(
+            "currencies.csv",
+            &[
+                "client,available,held,total,locked,in_debt,currency",
+                "0,50.0,0.0,50.0,false,false,\n",
+                "0,0.0,10.0,10.0,false,false,EUR\n",
+                "0,20.0,0.0,20.0,false,false,USD\n",
+                "1,0.0,0.0,0.0,false,false,\n",
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fees_are_collected_in_house_account() {
+        let mut fee_schedule = read_fee_schedule("fees.csv").unwrap();
+        fee_schedule.set_tiers(read_tiers("tiers.csv").unwrap());
+        run_payment_engine_with_config(
+            "fees-input.csv",
+            Config {
+                fee_schedule,
+                ..Default::default()
+            },
+            &[
+                "0,88.9000,0.0,88.9000,false,false,\n",
+                "1,49.5000,0.0,49.5000,true,false,\n",
+                "1,-15.0,0.0,-15.0,true,true,EUR\n",
+                "house,1.6000,0.0,1.6000,false,false,\n",
+                "house,15.0,0.0,15.0,false,false,EUR\n",
             ],
         )
         .await;
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_transfers() {
+        let audit_file = temp_file("payment-engine-test-transfers.csv");
+        run_payment_engine_with_config(
+            "transfers.csv",
+            Config {
+                audit_file: Some(audit_file.clone()),
+                ..Default::default()
+            },
+            &[
+                "0,6.0,0.0,6.0,false,false,\n",
+                "1,7.0,0.0,7.0,true,false,\n",
+                "2,3.0,0.0,3.0,false,false,\n",
+            ],
+        )
+        .await;
+
+        let audit = std::fs::read_to_string(&audit_file).unwrap();
+        for line in [
+            "5,transfer,0,5,applied,\n",
+            "6,transfer,1,6,applied,\n",
+            "7,transfer,2,7,rejected,InsufficientFunds\n",
+            "8,transfer,0,8,rejected,InvalidTransfer\n",
+            "12,transfer,0,12,rejected,CounterpartyRejected\n",
+            "13,transfer,1,13,rejected,AccountFrozen\n",
+        ] {
+            assert!(audit.contains(line), "{} missing in {}", line, audit);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_explain() {
+        run_payment_engine_with_config(
+            "transfers.csv",
+            Config {
+                explain_client: Some(0),
+                ..Default::default()
+            },
+            &[
+                "line,type,tx,currency,amount,counterparty,outcome,reason,available,held,total\n\
+               2,deposit,1,,10.0,,applied,,10.0,0.0,10.0\n\
+               5,transfer,5,,4.0,1,applied,,6.0,0.0,6.0\n\
+               7,transfer,7,,4.0,2,rejected,CounterpartyRejected,6.0,0.0,6.0\n\
+               8,transfer,8,,1.0,0,rejected,InvalidTransfer,6.0,0.0,6.0\n\
+               12,transfer,12,,1.0,1,rejected,CounterpartyRejected,6.0,0.0,6.0\n\
+               13,transfer,13,,1.0,1,rejected,CounterpartyRejected,6.0,0.0,6.0\n",
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_transfers_single_thread() {
+        run_payment_engine(
+            "transfers.csv",
+            &[
+                "0,6.0,0.0,6.0,false,false,\n",
+                "1,7.0,0.0,7.0,true,false,\n",
+                "2,3.0,0.0,3.0,false,false,\n",
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rules() {
+        let flag_file = temp_file("payment-engine-test-flags.csv");
+        run_payment_engine_with_config(
+            "rules-input.csv",
+            Config {
+                rules: read_rules("rules.csv").unwrap(),
+                flag_file: Some(flag_file.clone()),
+                ..Default::default()
+            },
+            &[
+                "1,4997.0,0.0,4997.0,false,false,\n",
+                "2,1.0,0.0,1.0,false,false,\n",
+            ],
+        )
+        .await;
+
+        let flags = std::fs::read_to_string(&flag_file).unwrap();
+        assert!(flags.starts_with("line,rule,action,type,client,tx\n"));
+        assert!(flags.contains("3,large-deposit,flag,withdrawal,1,2\n"));
+        assert!(flags.contains("6,velocity,block,withdrawal,1,5\n"));
+        assert_eq!(flags.lines().count(), 3);
+    }
+
+    async fn compute_sorted_output(filename: &str, config: Config) -> Vec<String> {
+        let mut buf = BufWriter::new(Vec::new());
+        {
+            let mut writer = Writer::from_writer(&mut buf);
+            read_file_and_output_to_writer(filename, &mut writer, Some(2), config)
+                .await
+                .expect("Failed to compute file");
+            writer.flush().unwrap();
+        }
+
+        let bytes = buf.into_inner().unwrap();
+        let mut lines: Vec<String> = String::from_utf8(bytes)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_disk_store_has_same_results() {
+        let mut fee_schedule = read_fee_schedule("fees.csv").unwrap();
+        fee_schedule.set_tiers(read_tiers("tiers.csv").unwrap());
+        let config = Config {
+            dispute_policy: DisputePolicy::DepositsAndWithdrawals,
+            credit_limits: read_credit_limits("credit-limits.csv").unwrap(),
+            fee_schedule,
+            retention: RetentionPolicy {
+                max_transactions: Some(2),
+                ..Default::default()
+            },
+            rules: read_rules("rules.csv").unwrap(),
+            ..Default::default()
+        };
+        for filename in [
+            "overdraft.csv",
+            "fees-input.csv",
+            "duplicate-tx.csv",
+            "transfers.csv",
+        ] {
+            let spill_dir = temp_file(&format!("payment-engine-test-spill-{}", filename));
+            let disk_config = Config {
+                spill_dir: Some(spill_dir.clone()),
+                ..config.clone()
+            };
+            let output = compute_sorted_output(filename, config.clone()).await;
+            assert!(output.len() > 1, "{}", filename);
+            assert_eq!(
+                output,
+                compute_sorted_output(filename, disk_config.clone()).await,
+                "{}",
+                filename
+            );
+            assert!(
+                records_written(filename, disk_config).await > 0,
+                "{}",
+                filename
+            );
+        }
+    }
+
+    // Apply the operations of `filename` like `read_file_and_output_to_writer` and return the number of records
+    // written to the segment files, to check that the disk was actually used.
+    async fn records_written(filename: &str, config: Config) -> u64 {
+        let world = ClientHandles::new(config).unwrap();
+        let spill_dir = world.spill_dir.clone().expect("No spill dir configured");
+        let world = Arc::new(Mutex::new(world));
+
+        let data = std::fs::read(filename).unwrap();
+        // Skip the header.
+        let start = data.iter().position(|&byte| byte == b'\n').unwrap() + 1;
+        let (mut operations, unparseable) = parse_csv(&data[start..], 1024, 2);
+        perform_work(&mut operations, unparseable, world.clone()).await;
+        world
+            .lock()
+            .await
+            .serialize_work(&mut Writer::from_writer(io::sink()))
+            .await
+            .unwrap();
+        spill_dir.records_written()
+    }
+
+    // Processing a file after restoring the snapshot of the previous file gives the same result as processing
+    // both files at once.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_restore_snapshot() {
+        // The owners of the transaction ids are in the snapshot even if the first day doesn't check them.
+        for day1_policy in [DuplicateTxPolicy::Reject, DuplicateTxPolicy::Off] {
+            restore_snapshot(day1_policy).await;
+        }
+    }
+
+    async fn restore_snapshot(day1_policy: DuplicateTxPolicy) {
+        let config = Config {
+            duplicate_tx_policy: DuplicateTxPolicy::Reject,
+            ..Default::default()
+        };
+        let snapshot = temp_file("payment-engine-test-restore-snapshot.json");
+        compute_sorted_output(
+            "snapshot-day1.csv",
+            Config {
+                snapshot_file: Some(snapshot.clone()),
+                duplicate_tx_policy: day1_policy,
+                ..config.clone()
+            },
+        )
+        .await;
+        let restored = compute_sorted_output(
+            "snapshot-day2.csv",
+            Config {
+                restore_file: Some(snapshot.clone()),
+                ..config.clone()
+            },
+        )
+        .await;
+        std::fs::remove_file(&snapshot).unwrap();
+
+        let both = temp_file("payment-engine-test-restore-both.csv");
+        let day2 = std::fs::read_to_string("snapshot-day2.csv").unwrap();
+        let day2 = day2.split_once('\n').unwrap().1;
+        std::fs::write(
+            &both,
+            std::fs::read_to_string("snapshot-day1.csv").unwrap() + day2,
+        )
+        .unwrap();
+        let expected = compute_sorted_output(&both, config).await;
+        std::fs::remove_file(&both).unwrap();
+
+        assert_eq!(restored, expected, "{:?}", day1_policy);
+        // The deposit of the first day was charged back on the second day.
+        assert!(restored.contains(&"1,-20.0,0.0,-20.0,true,true,".to_string()));
+    }
+
+    // The latest event of each client and currency has the funds of the output.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_events_match_output() {
+        for filename in [
+            "currencies.csv",
+            "transfers.csv",
+            "charged-back-withdrawal.csv",
+        ] {
+            let path = temp_file(&format!("payment-engine-test-events-{}.jsonl", filename));
+            let config = Config {
+                event_sink: Some(SharedEventSink::new(JsonlEventSink::create(&path).unwrap())),
+                ..Default::default()
+            };
+            let output = compute_sorted_output(filename, config).await;
+            let events = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            let mut latest = HashMap::new();
+            for line in events.lines() {
+                let event: serde_json::Value = serde_json::from_str(line).unwrap();
+                latest.insert(
+                    (event["client"].to_string(), event["currency"].clone()),
+                    event,
+                );
+            }
+            assert!(!latest.is_empty(), "{}", filename);
+            for ((client, currency), event) in latest {
+                let available = event["available"].as_str().unwrap();
+                let held = event["held"].as_str().unwrap();
+                let prefix = format!("{},{},{},", client, available, held);
+                assert!(
+                    output.iter().any(|row| row.starts_with(&prefix)
+                        && row.ends_with(&format!(",{}", currency.as_str().unwrap()))),
+                    "{}: {} not in {:?}",
+                    filename,
+                    prefix,
+                    output
+                );
+            }
+        }
+    }
+
+    // The client and house accounts of the journal have the funds of the output.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_journal_matches_output() {
+        let path = temp_file("payment-engine-test-journal-fees.csv");
+        let mut fee_schedule = read_fee_schedule("fees.csv").unwrap();
+        fee_schedule.set_tiers(read_tiers("tiers.csv").unwrap());
+        let config = Config {
+            fee_schedule,
+            journal_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let output = compute_sorted_output("fees-input.csv", config).await;
+
+        let mut balances: BTreeMap<(String, String, String), Amount> = BTreeMap::new();
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        for record in reader.records() {
+            let record = record.unwrap();
+            let (client, currency, account) = (&record[2], &record[4], &record[5]);
+            let owner = match account {
+                "client-available" | "client-held" => client,
+                _ => "",
+            };
+            let balance = balances
+                .entry((account.to_string(), owner.to_string(), currency.to_string()))
+                .or_default();
+            let (debit, credit) = (record[6].parse().unwrap(), record[7].parse().unwrap());
+            *balance = balance
+                .checked_add(debit)
+                .unwrap()
+                .checked_sub(credit)
+                .unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let balance = |account: &str, owner: &str, currency: &str| {
+            balances[&(account.to_string(), owner.to_string(), currency.to_string())].to_string()
+        };
+        assert_eq!(balance("client-available", "0", ""), "88.9000");
+        assert_eq!(balance("client-available", "1", "EUR"), "-15.0");
+        assert_eq!(balance("house", "", ""), "1.6000");
+        assert_eq!(balance("house", "", "EUR"), "15.0");
+        assert!(output.contains(&"0,88.9000,0.0,88.9000,false,false,".to_string()));
+        for currency in ["", "EUR"] {
+            let sum: Amount = balances
+                .iter()
+                .filter(|((_, _, c), _)| c == currency)
+                .fold(Amount::ZERO, |sum, (_, balance)| {
+                    sum.checked_add(*balance).unwrap()
+                });
+            assert_eq!(sum, Amount::ZERO);
+        }
+    }
 }
@@ -1,5 +1,6 @@
 use std::env;
 use std::io::stdout;
+use std::process::exit;
 use std::vec::Vec;
 
 use csv::Writer;
@@ -7,14 +8,118 @@ use tokio::io;
 
 use payment_engine::*;
 
+// The value of an option that changes how amounts are parsed.
+fn amount_option<'a>(args: &'a [String], name: &str) -> Result<Option<&'a String>, String> {
+    match args.iter().position(|arg| arg == name) {
+        Some(position) => args
+            .get(position + 1)
+            .map(Some)
+            .ok_or_else(|| format!("Missing value for {}.", name)),
+        None => Ok(None),
+    }
+}
+
+// Parse the command line: the csv filename followed by optional `--name value` pairs.
+fn parse_args(args: &[String]) -> Result<(String, Config), String> {
+    let mut filename = None;
+    let mut config = Config::default();
+
+    // The decimals and rounding have to be known before any amount is parsed, e.g. in the credit limits.
+    if let Some(value) = amount_option(args, "--decimals")? {
+        let decimals = value
+            .parse()
+            .map_err(|_| format!("Invalid number of decimals '{}'.", value))?;
+        set_decimals(decimals)?;
+        config.decimals = Some(decimals);
+    }
+    if let Some(value) = amount_option(args, "--rounding")? {
+        config.rounding = value.parse()?;
+        set_rounding(config.rounding)?;
+    }
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            filename = Some(arg.clone());
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}.", arg))?;
+        match arg.as_str() {
+            "--decimals" | "--rounding" => {}
+            "--dispute-policy" => config.dispute_policy = value.parse()?,
+            "--duplicate-tx" => config.duplicate_tx_policy = value.parse()?,
+            "--limits" => {
+                config.credit_limits = read_credit_limits(value)
+                    .map_err(|err| format!("Failed to read credit limits with {}", err))?
+            }
+            "--fees" => {
+                let tiers = config.fee_schedule.tiers().clone();
+                config.fee_schedule = read_fee_schedule(value)
+                    .map_err(|err| format!("Failed to read fee schedule with {}", err))?;
+                config.fee_schedule.set_tiers(tiers);
+            }
+            "--tiers" => config.fee_schedule.set_tiers(
+                read_tiers(value).map_err(|err| format!("Failed to read tiers with {}", err))?,
+            ),
+            "--dispute-window" => config.dispute_window = Some(parse_duration(value)?),
+            "--retain-transactions" => {
+                config.retention.max_transactions = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid transaction count '{}'.", value))?,
+                )
+            }
+            "--retain-for" => config.retention.max_age = Some(value.parse()?),
+            "--as-of" => config.as_of = Some(value.parse()?),
+            "--explain" => {
+                config.explain_client = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid client '{}'.", value))?,
+                )
+            }
+            "--spill-dir" => config.spill_dir = Some(value.clone()),
+            "--restore" => config.restore_file = Some(value.clone()),
+            "--snapshot" => config.snapshot_file = Some(value.clone()),
+            "--rules" => {
+                config.rules =
+                    read_rules(value).map_err(|err| format!("Failed to read rules with {}", err))?
+            }
+            "--flags" => config.flag_file = Some(value.clone()),
+            "--events" => {
+                config.event_sink = Some(SharedEventSink::new(
+                    JsonlEventSink::create(value)
+                        .map_err(|err| format!("Failed to create event file with {}", err))?,
+                ))
+            }
+            "--journal" => config.journal_file = Some(value.clone()),
+            "--audit" => config.audit_file = Some(value.clone()),
+            "--anomalies" => config.anomaly_file = Some(value.clone()),
+            _ => return Err(format!("Unknown option {}.", arg)),
+        }
+    }
+
+    let filename = filename.ok_or("At least one argument required (The csv filename)")?;
+    Ok((filename, config))
+}
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
     let args: Vec<String> = env::args().collect();
-    let filename = args
-        .get(1)
-        .expect("At least one argument required (The csv filename)");
+    let (filename, config) = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    };
     let mut writer = Writer::from_writer(stdout());
-    if let Err(err) = read_file_and_output_to_writer(filename.as_str(), &mut writer, None).await {
+    if let Err(err) =
+        read_file_and_output_to_writer(filename.as_str(), &mut writer, None, config).await
+    {
         eprintln!("Failed to run payment engine with {}", err);
     }
     Ok(())
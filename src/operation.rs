@@ -1,9 +1,10 @@
-use crate::serialize_fractional::serialize_fractional;
-use serde::de::Error;
-use serde::{Deserialize, Deserializer, Serialize};
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::timestamp::Timestamp;
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[repr(u16)]
 pub enum OperationType {
     #[serde(rename = "deposit")]
@@ -16,37 +17,95 @@ pub enum OperationType {
     Resolve,
     #[serde(rename = "chargeback")]
     Chargeback,
+    #[serde(rename = "refund")]
+    Refund,
+    #[serde(rename = "transfer")]
+    Transfer,
+    #[serde(rename = "authorize")]
+    Authorize,
+    #[serde(rename = "capture")]
+    Capture,
+    #[serde(rename = "void")]
+    Void,
+    #[serde(rename = "unlock")]
+    Unlock,
+    #[serde(rename = "close")]
+    Close,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+impl OperationType {
+    // Whether the operation introduces a new transaction id. The other operations reference an existing one.
+    pub fn creates_transaction(self) -> bool {
+        matches!(
+            self,
+            OperationType::Deposit
+                | OperationType::Withdrawal
+                | OperationType::Transfer
+                | OperationType::Authorize
+        )
+    }
+
+    // Whether the transaction id of the operation references the transaction of an earlier operation.
+    pub fn references_transaction(self) -> bool {
+        matches!(
+            self,
+            OperationType::Dispute
+                | OperationType::Resolve
+                | OperationType::Chargeback
+                | OperationType::Refund
+                | OperationType::Capture
+                | OperationType::Void
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Operation {
     pub type_: OperationType,
     pub client: u16,
     pub tx_id: u32,
-    #[serde(
-        deserialize_with = "deserialize_amount",
-        serialize_with = "serialize_fractional"
-    )]
-    pub amount: i64,
+    pub amount: Amount,
+    // The currency column is optional.
+    #[serde(default)]
+    pub currency: Currency,
+    // The receiving client of a transfer.
+    #[serde(default)]
+    pub to: Option<u16>,
+    // The time of the operation. The timestamp column is optional.
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+}
+
+// An operation together with the line of the input it was read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputOperation {
+    pub line: u64,
+    pub operation: Operation,
 }
 
 #[cfg(test)]
 impl Operation {
-    pub fn deposit(client: u16, tx_id: u32, amount: i64) -> Operation {
+    pub fn deposit(client: u16, tx_id: u32, amount: i128) -> Operation {
         Operation {
             type_: OperationType::Deposit,
             client,
             tx_id,
-            amount,
+            amount: Amount::from_units(amount),
+            currency: Currency::default(),
+            to: None,
+            timestamp: None,
         }
     }
 
-    pub fn withdrawal(client: u16, tx_id: u32, amount: i64) -> Operation {
+    pub fn withdrawal(client: u16, tx_id: u32, amount: i128) -> Operation {
         Operation {
             type_: OperationType::Withdrawal,
             client,
             tx_id,
-            amount,
+            amount: Amount::from_units(amount),
+            currency: Currency::default(),
+            to: None,
+            timestamp: None,
         }
     }
 
@@ -55,7 +114,10 @@ impl Operation {
             type_: OperationType::Dispute,
             client,
             tx_id,
-            amount: 0,
+            amount: Amount::ZERO,
+            currency: Currency::default(),
+            to: None,
+            timestamp: None,
         }
     }
 
@@ -64,7 +126,10 @@ impl Operation {
             type_: OperationType::Resolve,
             client,
             tx_id,
-            amount: 0,
+            amount: Amount::ZERO,
+            currency: Currency::default(),
+            to: None,
+            timestamp: None,
         }
     }
 
@@ -73,35 +138,117 @@ impl Operation {
             type_: OperationType::Chargeback,
             client,
             tx_id,
-            amount: 0,
+            amount: Amount::ZERO,
+            currency: Currency::default(),
+            to: None,
+            timestamp: None,
         }
     }
-}
 
-fn deserialize_amount<'de, D>(deserializer: D) -> Result<i64, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s: &str = Deserialize::deserialize(deserializer)?;
+    pub fn with_amount(self, amount: i128) -> Operation {
+        Operation {
+            amount: Amount::from_units(amount),
+            ..self
+        }
+    }
+
+    pub fn with_timestamp(self, timestamp: &str) -> Operation {
+        Operation {
+            timestamp: Some(timestamp.parse().unwrap()),
+            ..self
+        }
+    }
+
+    pub fn with_currency(self, currency: &str) -> Operation {
+        Operation {
+            currency: currency.parse().unwrap(),
+            ..self
+        }
+    }
+
+    pub fn refund(client: u16, tx_id: u32) -> Operation {
+        Operation {
+            type_: OperationType::Refund,
+            client,
+            tx_id,
+            amount: Amount::ZERO,
+            currency: Currency::default(),
+            to: None,
+            timestamp: None,
+        }
+    }
+
+    pub fn transfer(client: u16, tx_id: u32, amount: i128, to: u16) -> Operation {
+        Operation {
+            type_: OperationType::Transfer,
+            client,
+            tx_id,
+            amount: Amount::from_units(amount),
+            currency: Currency::default(),
+            to: Some(to),
+            timestamp: None,
+        }
+    }
+
+    pub fn authorize(client: u16, tx_id: u32, amount: i128) -> Operation {
+        Operation {
+            type_: OperationType::Authorize,
+            client,
+            tx_id,
+            amount: Amount::from_units(amount),
+            currency: Currency::default(),
+            to: None,
+            timestamp: None,
+        }
+    }
 
-    let mut parts = s.split('.');
+    pub fn capture(client: u16, tx_id: u32) -> Operation {
+        Operation {
+            type_: OperationType::Capture,
+            client,
+            tx_id,
+            amount: Amount::ZERO,
+            currency: Currency::default(),
+            to: None,
+            timestamp: None,
+        }
+    }
 
-    let mut amount: i64;
+    pub fn void(client: u16, tx_id: u32) -> Operation {
+        Operation {
+            type_: OperationType::Void,
+            client,
+            tx_id,
+            amount: Amount::ZERO,
+            currency: Currency::default(),
+            to: None,
+            timestamp: None,
+        }
+    }
 
-    let integer_part = parts
-        .next()
-        .ok_or_else(|| D::Error::custom("Failed to parse integer part."))?
-        .parse::<u32>()
-        .map_err(|_| D::Error::custom("Failed to parse integer part."))?;
-    amount = (integer_part as i64) * 10000;
-    if let Some(fractional) = parts.next() {
-        let fractional_part = format!("{:0<4}", fractional)
-            .parse::<u16>()
-            .map_err(|_| D::Error::custom("Failed to parse fractional part."))?;
-        amount += fractional_part as i64;
+    pub fn unlock(client: u16, tx_id: u32) -> Operation {
+        Operation {
+            type_: OperationType::Unlock,
+            client,
+            tx_id,
+            amount: Amount::ZERO,
+            currency: Currency::default(),
+            to: None,
+            timestamp: None,
+        }
     }
 
-    Ok(amount)
+    pub fn close(client: u16, tx_id: u32) -> Operation {
+        Operation {
+            type_: OperationType::Close,
+            client,
+            tx_id,
+            amount: Amount::ZERO,
+            currency: Currency::default(),
+            to: None,
+            timestamp: None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -118,14 +265,20 @@ deposit,1,2,1.0
 withdrawal,2,3,5.0
 dispute,3,4,1.2340
 resolve,5,6,1.3333
-chargeback,7,8,4294967295.9999";
+chargeback,7,8,4294967295.9999
+unlock,9,10,
+close,11,12,0
+authorize,13,14,2.5
+capture,13,14,
+void,13,14,
+refund,15,16,0.5";
         let mut reader = ReaderBuilder::new().from_reader(buf.as_bytes());
         let operations: Vec<Operation> = reader
             .byte_records()
             .map(|record| record.unwrap().deserialize(None).unwrap())
             .collect();
 
-        assert_eq!(operations.len(), 5);
+        assert_eq!(operations.len(), 11);
         assert_eq!(
             operations,
             vec![
@@ -133,68 +286,104 @@ chargeback,7,8,4294967295.9999";
                     type_: OperationType::Deposit,
                     client: 1,
                     tx_id: 2,
-                    amount: 10000
+                    amount: Amount::from_units(10000),
+                    currency: Currency::default(),
+                    to: None,
+                    timestamp: None
                 },
                 Operation {
                     type_: OperationType::Withdrawal,
                     client: 2,
                     tx_id: 3,
-                    amount: 50000
+                    amount: Amount::from_units(50000),
+                    currency: Currency::default(),
+                    to: None,
+                    timestamp: None
                 },
                 Operation {
                     type_: OperationType::Dispute,
                     client: 3,
                     tx_id: 4,
-                    amount: 12340
+                    amount: Amount::from_units(12340),
+                    currency: Currency::default(),
+                    to: None,
+                    timestamp: None
                 },
                 Operation {
                     type_: OperationType::Resolve,
                     client: 5,
                     tx_id: 6,
-                    amount: 13333
+                    amount: Amount::from_units(13333),
+                    currency: Currency::default(),
+                    to: None,
+                    timestamp: None
                 },
                 Operation {
                     type_: OperationType::Chargeback,
                     client: 7,
                     tx_id: 8,
-                    amount: 42949672959999
+                    amount: Amount::from_units(42949672959999),
+                    currency: Currency::default(),
+                    to: None,
+                    timestamp: None
                 },
+                Operation::unlock(9, 10),
+                Operation::close(11, 12),
+                Operation::authorize(13, 14, 25000),
+                Operation::capture(13, 14),
+                Operation::void(13, 14),
+                Operation::refund(15, 16).with_amount(5000),
             ]
         );
     }
 
     #[test]
     fn test_serialize_operation() {
-        let operations = vec![
+        let operations = [
             Operation {
                 type_: OperationType::Deposit,
                 client: 1,
                 tx_id: 2,
-                amount: 10000,
+                amount: Amount::from_units(10000),
+                currency: Currency::default(),
+                to: None,
+                timestamp: None,
             },
             Operation {
                 type_: OperationType::Withdrawal,
                 client: 2,
                 tx_id: 3,
-                amount: 50000,
+                amount: Amount::from_units(50000),
+                currency: Currency::default(),
+                to: None,
+                timestamp: None,
             },
             Operation {
                 type_: OperationType::Dispute,
                 client: 3,
                 tx_id: 4,
-                amount: 12340,
+                amount: Amount::from_units(12340),
+                currency: Currency::default(),
+                to: None,
+                timestamp: None,
             },
             Operation {
                 type_: OperationType::Resolve,
                 client: 5,
                 tx_id: 6,
-                amount: -13333,
+                amount: Amount::from_units(-13333),
+                currency: Currency::default(),
+                to: None,
+                timestamp: None,
             },
             Operation {
                 type_: OperationType::Chargeback,
                 client: 7,
                 tx_id: 8,
-                amount: 42949672959999,
+                amount: Amount::from_units(42949672959999),
+                currency: Currency::default(),
+                to: None,
+                timestamp: None,
             },
         ];
 
@@ -207,12 +396,12 @@ chargeback,7,8,4294967295.9999";
             }
         }
 
-        let result = "type_,client,tx_id,amount
-deposit,1,2,1.0
-withdrawal,2,3,5.0
-dispute,3,4,1.2340
-resolve,5,6,-1.3333
-chargeback,7,8,4294967295.9999
+        let result = "type_,client,tx_id,amount,currency,to,timestamp
+deposit,1,2,1.0,,,
+withdrawal,2,3,5.0,,,
+dispute,3,4,1.2340,,,
+resolve,5,6,-1.3333,,,
+chargeback,7,8,4294967295.9999,,,
 ";
 
         let bytes = buf.into_inner().unwrap();
@@ -220,4 +409,34 @@ chargeback,7,8,4294967295.9999
 
         assert_eq!(string.as_str(), result);
     }
+
+    #[test]
+    fn test_deserialize_currency() {
+        let buf = "deposit,1,2,1.0,EUR
+withdrawal,2,3,5.0,
+dispute,3,4,
+transfer,5,6,1.0,EUR,7
+deposit,8,9,1.0,,,2022-01-31T12:00:00Z
+dispute,8,9,,,,1643630401";
+        let mut reader = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(buf.as_bytes());
+        let operations: Vec<Operation> = reader
+            .byte_records()
+            .map(|record| record.unwrap().deserialize(None).unwrap())
+            .collect();
+
+        assert_eq!(
+            operations,
+            vec![
+                Operation::deposit(1, 2, 10000).with_currency("EUR"),
+                Operation::withdrawal(2, 3, 50000),
+                Operation::dispute(3, 4),
+                Operation::transfer(5, 6, 10000, 7).with_currency("EUR"),
+                Operation::deposit(8, 9, 10000).with_timestamp("1643630400"),
+                Operation::dispute(8, 9).with_timestamp("1643630401"),
+            ]
+        );
+    }
 }
@@ -99,11 +99,11 @@ mod tests {
             let mut buf = Vec::new();
             let result = read_num_lines(&mut reader, 1, &mut buf);
             assert_eq!(result.unwrap(), 6);
-            assert_eq!(buf.as_bytes(), buffer[0..6].as_bytes());
+            assert_eq!(buf.as_bytes(), &buffer.as_bytes()[0..6]);
             let mut buf2 = Vec::new();
             let result2 = read_num_lines(&mut reader, 1, &mut buf2);
             assert_eq!(result2.unwrap(), 5);
-            assert_eq!(buf2.as_bytes(), buffer[6..11].as_bytes());
+            assert_eq!(buf2.as_bytes(), &buffer.as_bytes()[6..11]);
         }
     }
 
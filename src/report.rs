@@ -0,0 +1,82 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use csv::Writer;
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ReportFormat {
+    Csv,
+    Jsonl,
+}
+
+impl ReportFormat {
+    // Files ending with `.jsonl` are written as json lines, everything else as csv.
+    fn from_path(path: &str) -> ReportFormat {
+        match Path::new(path).extension() {
+            Some(extension) if extension == "jsonl" => ReportFormat::Jsonl,
+            _ => ReportFormat::Csv,
+        }
+    }
+}
+
+// Writes records produced by the per-client futures into a file.
+// The records are sent in batches over a channel and written by a blocking task, so the client futures
+// never wait for the file system.
+pub struct ReportWriter<T> {
+    sender: Sender<Vec<T>>,
+    handle: JoinHandle<io::Result<()>>,
+}
+
+impl<T: Serialize + Send + 'static> ReportWriter<T> {
+    pub fn create(path: &str) -> io::Result<ReportWriter<T>> {
+        let file = BufWriter::new(File::create(path)?);
+        let format = ReportFormat::from_path(path);
+        let (sender, receiver) = channel();
+        let handle = tokio::task::spawn_blocking(move || write_records(file, format, receiver));
+
+        Ok(ReportWriter { sender, handle })
+    }
+
+    pub fn sender(&self) -> Sender<Vec<T>> {
+        self.sender.clone()
+    }
+
+    // Wait until all records are written. All senders must have been dropped before, otherwise this never finishes.
+    pub async fn finish(self) -> io::Result<()> {
+        drop(self.sender);
+        self.handle.await?
+    }
+}
+
+fn write_records<T: Serialize, W: Write>(
+    write: W,
+    format: ReportFormat,
+    receiver: Receiver<Vec<T>>,
+) -> io::Result<()> {
+    match format {
+        ReportFormat::Csv => {
+            let mut writer = Writer::from_writer(write);
+            for records in receiver.iter() {
+                for record in records.iter() {
+                    writer.serialize(record)?;
+                }
+            }
+            writer.flush()
+        }
+        ReportFormat::Jsonl => {
+            let mut writer = write;
+            for records in receiver.iter() {
+                for record in records.iter() {
+                    serde_json::to_writer(&mut writer, record)?;
+                    writer.write_all(b"\n")?;
+                }
+            }
+            writer.flush()
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io;
+use std::sync::Arc;
+
+use csv::{ReaderBuilder, StringRecord, Trim};
+use serde::{Deserialize, Serialize};
+
+use crate::amount::{scale, Amount};
+use crate::operation::{Operation, OperationType};
+
+// What happens to an operation matching a rule. Every match is reported.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    // The operation is applied.
+    Flag,
+    // The operation is rejected.
+    Block,
+    // The operation is applied, then the account is frozen.
+    Freeze,
+}
+
+// What the rules know about the operations a client applied so far.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct Activity {
+    // The latest operations, oldest first. Only as many as the rules look at are kept.
+    recent: VecDeque<Operation>,
+    deposits: u64,
+    disputes: u64,
+}
+
+impl Activity {
+    // Record an applied operation, keeping the latest `history` operations.
+    pub fn record(&mut self, operation: &Operation, history: usize) {
+        match operation.type_ {
+            OperationType::Deposit => self.deposits += 1,
+            OperationType::Dispute => self.disputes += 1,
+            _ => {}
+        }
+
+        if history == 0 {
+            return;
+        }
+        if self.recent.len() >= history {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(*operation);
+    }
+
+    // The latest `count` operations, latest first.
+    fn latest(&self, count: usize) -> impl Iterator<Item = &Operation> {
+        self.recent.iter().rev().take(count)
+    }
+}
+
+// A suspicious pattern of operations, e.g. many withdrawals in a row.
+pub trait Rule: Debug + Send + Sync {
+    // Whether `operation` completes the pattern, given the earlier activity of the client.
+    fn matches(&self, activity: &Activity, operation: &Operation) -> bool;
+
+    // How many of the latest operations the rule looks at.
+    fn history(&self) -> usize {
+        0
+    }
+}
+
+// More than `limit` withdrawals within the latest `window` operations (including the withdrawal itself).
+#[derive(Debug)]
+struct WithdrawalVelocity {
+    limit: usize,
+    window: usize,
+}
+
+impl Rule for WithdrawalVelocity {
+    fn matches(&self, activity: &Activity, operation: &Operation) -> bool {
+        if operation.type_ != OperationType::Withdrawal {
+            return false;
+        }
+        let earlier = activity
+            .latest(self.history())
+            .filter(|operation| operation.type_ == OperationType::Withdrawal)
+            .count();
+        earlier + 1 > self.limit
+    }
+
+    fn history(&self) -> usize {
+        self.window.saturating_sub(1)
+    }
+}
+
+// A withdrawal within the `window` operations following a deposit of at least `amount`.
+#[derive(Debug)]
+struct WithdrawalAfterLargeDeposit {
+    amount: Amount,
+    window: usize,
+}
+
+impl Rule for WithdrawalAfterLargeDeposit {
+    fn matches(&self, activity: &Activity, operation: &Operation) -> bool {
+        operation.type_ == OperationType::Withdrawal
+            && activity.latest(self.window).any(|operation| {
+                operation.type_ == OperationType::Deposit && operation.amount >= self.amount
+            })
+    }
+
+    fn history(&self) -> usize {
+        self.window
+    }
+}
+
+// A dispute raising the number of disputes above `percentage` of the deposits. Only checked once the client
+// made `min_deposits` deposits.
+#[derive(Debug)]
+struct DisputeRate {
+    // The same decimals as amounts.
+    percentage: Amount,
+    min_deposits: u64,
+}
+
+impl Rule for DisputeRate {
+    fn matches(&self, activity: &Activity, operation: &Operation) -> bool {
+        if operation.type_ != OperationType::Dispute
+            || activity.deposits == 0
+            || activity.deposits < self.min_deposits
+        {
+            return false;
+        }
+        // Compared without division, so the rate is exact. A limit that overflows can't be exceeded.
+        let disputes = (activity.disputes as i128 + 1).checked_mul(100 * scale());
+        let limit = self
+            .percentage
+            .units()
+            .checked_mul(activity.deposits as i128);
+        match (disputes, limit) {
+            (Some(disputes), Some(limit)) => disputes > limit,
+            (None, Some(_)) => true,
+            (_, None) => false,
+        }
+    }
+}
+
+// A rule together with its name (used in the flag report) and action.
+#[derive(Debug)]
+pub struct ConfiguredRule {
+    pub name: String,
+    pub action: RuleAction,
+    pub rule: Box<dyn Rule>,
+}
+
+// A rule that matched an operation.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RuleMatch {
+    pub rule: String,
+    pub action: RuleAction,
+}
+
+// The rules every operation of a client is checked against before it is applied.
+// The rules are shared by all clients.
+#[derive(Debug, Clone, Default)]
+pub struct RuleEngine {
+    rules: Arc<Vec<ConfiguredRule>>,
+    // The number of latest operations the rules look at.
+    history: usize,
+}
+
+// Rules are identified by their name.
+impl PartialEq for RuleEngine {
+    fn eq(&self, other: &Self) -> bool {
+        self.rules.len() == other.rules.len()
+            && self
+                .rules
+                .iter()
+                .zip(other.rules.iter())
+                .all(|(a, b)| a.name == b.name && a.action == b.action)
+    }
+}
+
+impl RuleEngine {
+    pub fn new(rules: Vec<ConfiguredRule>) -> RuleEngine {
+        RuleEngine {
+            history: rules
+                .iter()
+                .map(|configured| configured.rule.history())
+                .max()
+                .unwrap_or(0),
+            rules: Arc::new(rules),
+        }
+    }
+
+    pub fn history(&self) -> usize {
+        self.history
+    }
+
+    // The rules matching `operation`.
+    pub fn evaluate(&self, activity: &Activity, operation: &Operation) -> Vec<RuleMatch> {
+        self.rules
+            .iter()
+            .filter(|configured| configured.rule.matches(activity, operation))
+            .map(|configured| RuleMatch {
+                rule: configured.name.clone(),
+                action: configured.action,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RuleRow {
+    name: String,
+    rule: String,
+    action: RuleAction,
+    threshold: String,
+    #[serde(default)]
+    window: Option<usize>,
+    #[serde(default)]
+    min_deposits: Option<u64>,
+}
+
+fn invalid_rule(row: &RuleRow, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid rule '{}': {}", row.name, reason),
+    )
+}
+
+fn parse_rule(row: &RuleRow) -> io::Result<Box<dyn Rule>> {
+    let rule: Box<dyn Rule> = match row.rule.as_str() {
+        "withdrawal-velocity" => Box::new(WithdrawalVelocity {
+            limit: row
+                .threshold
+                .parse()
+                .map_err(|_| invalid_rule(row, "the threshold must be a count"))?,
+            window: row
+                .window
+                .ok_or_else(|| invalid_rule(row, "missing window"))?,
+        }),
+        "withdrawal-after-large-deposit" => Box::new(WithdrawalAfterLargeDeposit {
+            amount: row
+                .threshold
+                .parse()
+                .map_err(|err: String| invalid_rule(row, &err))?,
+            window: row.window.unwrap_or(1),
+        }),
+        // The dispute rate doesn't look at a window of operations. A window would be silently ignored otherwise.
+        "dispute-rate" if row.window.is_some() => {
+            return Err(invalid_rule(
+                row,
+                "dispute-rate has no window, use min_deposits",
+            ))
+        }
+        "dispute-rate" => Box::new(DisputeRate {
+            percentage: row
+                .threshold
+                .parse()
+                .map_err(|err: String| invalid_rule(row, &err))?,
+            min_deposits: row.min_deposits.unwrap_or(0),
+        }),
+        _ => return Err(invalid_rule(row, "unknown rule")),
+    };
+    Ok(rule)
+}
+
+// Read the rules from a csv file with the columns `name,rule,action,threshold,window,min_deposits`.
+pub fn read_rules(path: &str) -> io::Result<RuleEngine> {
+    let mut reader = ReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .from_path(path)?;
+    let mut record = StringRecord::new();
+    let mut rules = Vec::new();
+
+    while reader.read_record(&mut record)? {
+        let row: RuleRow = record.deserialize(None)?;
+        rules.push(ConfiguredRule {
+            rule: parse_rule(&row)?,
+            name: row.name,
+            action: row.action,
+        });
+    }
+
+    Ok(RuleEngine::new(rules))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn activity(operations: &[Operation], history: usize) -> Activity {
+        let mut activity = Activity::default();
+        for operation in operations {
+            activity.record(operation, history);
+        }
+        activity
+    }
+
+    #[test]
+    fn test_withdrawal_velocity() {
+        // More than 2 withdrawals within 3 operations.
+        let rule = WithdrawalVelocity {
+            limit: 2,
+            window: 3,
+        };
+        let withdrawal = Operation::withdrawal(0, 4, 1);
+        let history = rule.history();
+        let operations = [
+            Operation::withdrawal(0, 1, 1),
+            Operation::deposit(0, 2, 1),
+            Operation::withdrawal(0, 3, 1),
+        ];
+        // The first withdrawal is out of the window.
+        assert!(!rule.matches(&activity(&operations, history), &withdrawal));
+        let operations = [
+            Operation::withdrawal(0, 1, 1),
+            Operation::withdrawal(0, 2, 1),
+        ];
+        assert!(rule.matches(&activity(&operations, history), &withdrawal));
+        assert!(!rule.matches(
+            &activity(&operations, history),
+            &Operation::deposit(0, 4, 1)
+        ));
+    }
+
+    #[test]
+    fn test_withdrawal_after_large_deposit() {
+        let rule = WithdrawalAfterLargeDeposit {
+            amount: Amount::from_units(1000),
+            window: 1,
+        };
+        let withdrawal = Operation::withdrawal(0, 3, 1);
+        let large = activity(&[Operation::deposit(0, 1, 1000)], rule.history());
+        assert!(rule.matches(&large, &withdrawal));
+        let small = activity(&[Operation::deposit(0, 1, 999)], rule.history());
+        assert!(!rule.matches(&small, &withdrawal));
+        // Only the latest operation counts as "immediately".
+        let later = activity(
+            &[Operation::deposit(0, 1, 1000), Operation::deposit(0, 2, 1)],
+            rule.history(),
+        );
+        assert!(!rule.matches(&later, &withdrawal));
+    }
+
+    #[test]
+    fn test_dispute_rate() {
+        // More than 50% disputes, checked after 2 deposits.
+        let rule = DisputeRate {
+            percentage: Amount::from_units(500000),
+            min_deposits: 2,
+        };
+        let dispute = Operation::dispute(0, 1);
+        let one_deposit = activity(&[Operation::deposit(0, 1, 1)], 0);
+        assert!(!rule.matches(&one_deposit, &dispute));
+
+        let deposits = [
+            Operation::deposit(0, 1, 1),
+            Operation::deposit(0, 2, 1),
+            Operation::dispute(0, 1),
+        ];
+        assert!(!rule.matches(&activity(&deposits[..2], 0), &dispute));
+        assert!(rule.matches(&activity(&deposits, 0), &dispute));
+    }
+
+    #[test]
+    fn test_read_rules() {
+        let engine = read_rules("rules.csv").unwrap();
+        assert_eq!(engine.rules.len(), 3);
+        assert_eq!(engine.history(), 4);
+
+        let operations = [
+            Operation::deposit(0, 1, 50000000),
+            Operation::withdrawal(0, 2, 10000),
+        ];
+        let matches = engine.evaluate(&activity(&operations[..1], 4), &operations[1]);
+        assert_eq!(
+            matches,
+            vec![RuleMatch {
+                rule: "large-deposit".to_string(),
+                action: RuleAction::Flag
+            }]
+        );
+    }
+
+    #[test]
+    fn test_dispute_rate_takes_min_deposits() {
+        let mut row = RuleRow {
+            name: "dispute-rate".to_string(),
+            rule: "dispute-rate".to_string(),
+            action: RuleAction::Flag,
+            threshold: "50".to_string(),
+            window: None,
+            min_deposits: Some(2),
+        };
+        assert!(parse_rule(&row).is_ok());
+
+        row.window = Some(2);
+        assert_eq!(
+            parse_rule(&row).err().unwrap().to_string(),
+            "Invalid rule 'dispute-rate': dispute-rate has no window, use min_deposits"
+        );
+    }
+}
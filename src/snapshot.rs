@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+
+use serde::de::{DeserializeSeed, Error, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::amount::decimals;
+use crate::client_state::{ClientRestore, ClientSnapshotRef, ClientState};
+use crate::config::Config;
+use crate::transaction_store::AnyStore;
+
+// Snapshots of another version are rejected. Bump it whenever the format changes.
+const SNAPSHOT_VERSION: u32 = 1;
+
+// The state of the engine at the end of a run, written as json. A later run continues from it.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub clients: Vec<ClientState>,
+    // The client owning each transaction id seen so far, see `ClientHandles::check_tx_ids`.
+    pub tx_owners: Vec<(u32, u16)>,
+}
+
+// Writes a `Snapshot` from the client states. The stored transactions are read while they are written, so they are
+// never all in memory at once. The version and decimals come first, so a reader can check them before it restores
+// the clients.
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    version: u32,
+    // Amounts are stored with the decimals of the run that wrote the snapshot.
+    decimals: u32,
+    clients: Vec<ClientSnapshotRef<'a>>,
+    tx_owners: Vec<(u32, u16)>,
+}
+
+// The fields of a snapshot, see `SnapshotRef`.
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "snake_case")]
+enum SnapshotField {
+    Version,
+    Decimals,
+    Clients,
+    TxOwners,
+    #[serde(other)]
+    Unknown,
+}
+
+// Reads a snapshot while restoring its client states one by one, see `ClientRestore`.
+struct SnapshotRestore<'a>(ClientRestore<'a>);
+
+impl<'de> Visitor<'de> for SnapshotRestore<'_> {
+    type Value = Snapshot;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a snapshot")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Snapshot, A::Error> {
+        let mut version = None;
+        let mut decimals_ = None;
+        let mut snapshot = Snapshot {
+            clients: Vec::new(),
+            tx_owners: Vec::new(),
+        };
+        while let Some(field) = map.next_key()? {
+            match field {
+                SnapshotField::Version => version = Some(map.next_value()?),
+                SnapshotField::Decimals => decimals_ = Some(map.next_value()?),
+                SnapshotField::Clients => {
+                    // Amounts can't be read before the decimals are known to match.
+                    check_format(version, decimals_).map_err(A::Error::custom)?;
+                    snapshot.clients = map.next_value_seed(RestoredClients(self.0))?;
+                }
+                SnapshotField::TxOwners => snapshot.tx_owners = map.next_value()?,
+                SnapshotField::Unknown => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        check_format(version, decimals_).map_err(A::Error::custom)?;
+        Ok(snapshot)
+    }
+}
+
+// The client states of a snapshot, restored one by one while they are read.
+struct RestoredClients<'a>(ClientRestore<'a>);
+
+impl<'de> DeserializeSeed<'de> for RestoredClients<'_> {
+    type Value = Vec<ClientState>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for RestoredClients<'_> {
+    type Value = Vec<ClientState>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of client snapshots")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut clients = Vec::new();
+        while let Some(client_state) = seq.next_element_seed(self.0)? {
+            clients.push(client_state);
+        }
+        Ok(clients)
+    }
+}
+
+// Whether a snapshot of `version` with amounts of `snapshot_decimals` can be read by this run.
+fn check_format(version: Option<u32>, snapshot_decimals: Option<u32>) -> Result<(), String> {
+    match version {
+        Some(SNAPSHOT_VERSION) => {}
+        Some(version) => {
+            return Err(format!(
+                "Unsupported snapshot version {}, expected {}",
+                version, SNAPSHOT_VERSION
+            ))
+        }
+        None => return Err("The snapshot has no version".to_string()),
+    }
+    match snapshot_decimals {
+        Some(snapshot_decimals) if snapshot_decimals == decimals() => Ok(()),
+        Some(snapshot_decimals) => Err(format!(
+            "The snapshot uses {} decimals, but {} are configured",
+            snapshot_decimals,
+            decimals()
+        )),
+        None => Err("The snapshot has no decimals".to_string()),
+    }
+}
+
+impl Snapshot {
+    pub fn write(
+        path: &str,
+        clients: &[ClientState],
+        tx_owners: &HashMap<u32, u16>,
+    ) -> io::Result<()> {
+        let mut clients: Vec<ClientSnapshotRef> =
+            clients.iter().map(ClientState::snapshot).collect();
+        clients.sort_by_key(|client| client.client());
+        let mut tx_owners: Vec<(u32, u16)> = tx_owners
+            .iter()
+            .map(|(&tx_id, &client)| (tx_id, client))
+            .collect();
+        tx_owners.sort_unstable();
+        let snapshot = SnapshotRef {
+            version: SNAPSHOT_VERSION,
+            decimals: decimals(),
+            clients,
+            tx_owners,
+        };
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, &snapshot)?;
+        writer.flush()
+    }
+
+    // Continue from the snapshot in `path`. The file is streamed: the transactions of each client are inserted into
+    // the store created by `new_store` while they are read, so they are never all in memory at once.
+    pub fn read(
+        path: &str,
+        config: &Config,
+        new_store: &dyn Fn(u16) -> AnyStore,
+    ) -> io::Result<Snapshot> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+        let snapshot =
+            deserializer.deserialize_map(SnapshotRestore(ClientRestore { config, new_store }))?;
+        deserializer.end()?;
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_state::ClientState;
+    use crate::config::{Config, DisputePolicy, RetentionPolicy};
+    use crate::operation::Operation;
+    use crate::transaction_store::AnyStore;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let config = Config {
+            dispute_policy: DisputePolicy::DepositsAndWithdrawals,
+            retention: RetentionPolicy {
+                max_transactions: Some(3),
+                max_age: None,
+            },
+            ..Default::default()
+        };
+        let mut client = ClientState::with_config(1, &config);
+        for operation in [
+            Operation::deposit(1, 1, 100).with_timestamp("2022-01-31"),
+            Operation::withdrawal(1, 3, 80),
+            Operation::deposit(1, 2, 50).with_currency("EUR"),
+            // Leaves negative available funds.
+            Operation::dispute(1, 1),
+            // Evicts the withdrawal.
+            Operation::deposit(1, 4, 10),
+        ] {
+            client.apply_operation(operation).unwrap();
+        }
+
+        let path = std::env::temp_dir().join("payment-engine-test-snapshot.json");
+        let path = path.to_str().unwrap();
+        let tx_owners = [(1, 1), (2, 1), (3, 1), (4, 1)].into_iter().collect();
+        Snapshot::write(path, &[client.clone()], &tx_owners).unwrap();
+        let snapshot = Snapshot::read(path, &config, &|_| AnyStore::default()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(snapshot.tx_owners, vec![(1, 1), (2, 1), (3, 1), (4, 1)]);
+        let [mut restored]: [ClientState; 1] = snapshot.clients.try_into().unwrap();
+        assert_eq!(restored, client);
+        // The restored transactions can still be disputed.
+        assert_eq!(
+            restored.apply_operation(Operation::resolve(1, 1)),
+            client.apply_operation(Operation::resolve(1, 1))
+        );
+        assert_eq!(restored, client);
+    }
+
+    #[test]
+    fn test_other_version_is_rejected() {
+        let path = std::env::temp_dir().join("payment-engine-test-snapshot-version.json");
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            path,
+            r#"{"version":0,"decimals":4,"clients":[],"tx_owners":[]}"#,
+        )
+        .unwrap();
+        let result = Snapshot::read(path, &Config::default(), &|_| AnyStore::default());
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
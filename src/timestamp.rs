@@ -0,0 +1,206 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{Error, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// The time of an operation in seconds since the unix epoch (UTC).
+// It is parsed from unix seconds (`1643630400`) or a UTC date with an optional time (`2022-01-31` or
+// `2022-01-31T12:00:00Z`).
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    pub fn from_seconds(seconds: u64) -> Timestamp {
+        Timestamp(seconds)
+    }
+
+    pub fn seconds(&self) -> u64 {
+        self.0
+    }
+
+    // The seconds from `earlier` to `self`, or 0 if `earlier` is not earlier.
+    pub fn seconds_since(&self, earlier: Timestamp) -> u64 {
+        self.0.saturating_sub(earlier.0)
+    }
+}
+
+// The days between the unix epoch and the given date of the proleptic gregorian calendar.
+// See http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The number of days of the given month of the proleptic gregorian calendar.
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn parse_number(s: &str, length: usize, range: (u64, u64)) -> Option<u64> {
+    if s.len() != length || !s.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    s.parse()
+        .ok()
+        .filter(|number| (range.0..=range.1).contains(number))
+}
+
+fn parse_date_time(s: &str) -> Option<u64> {
+    let (date, time) = match s.split_once('T') {
+        Some((date, time)) => (date, Some(time.strip_suffix('Z')?)),
+        None => (s, None),
+    };
+
+    let mut date = date.split('-');
+    let year = parse_number(date.next()?, 4, (1970, 9999))?;
+    let month = parse_number(date.next()?, 2, (1, 12))?;
+    let day = parse_number(date.next()?, 2, (1, days_in_month(year, month)))?;
+    if date.next().is_some() {
+        return None;
+    }
+
+    let mut seconds = 0;
+    if let Some(time) = time {
+        let mut time = time.split(':');
+        let hours = parse_number(time.next()?, 2, (0, 23))?;
+        let minutes = parse_number(time.next()?, 2, (0, 59))?;
+        let secs = parse_number(time.next()?, 2, (0, 59))?;
+        if time.next().is_some() {
+            return None;
+        }
+        seconds = hours * 3600 + minutes * 60 + secs;
+    }
+
+    Some(days_from_civil(year, month, day) * SECONDS_PER_DAY + seconds)
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<u64>()
+            .ok()
+            .or_else(|| parse_date_time(s))
+            .map(Timestamp)
+            .ok_or_else(|| format!("Invalid timestamp '{}'.", s))
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0)
+    }
+}
+
+struct TimestampVisitor;
+
+impl Visitor<'_> for TimestampVisitor {
+    type Value = Timestamp;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("unix seconds or a UTC date")
+    }
+
+    fn visit_u64<E: Error>(self, seconds: u64) -> Result<Timestamp, E> {
+        Ok(Timestamp(seconds))
+    }
+
+    fn visit_str<E: Error>(self, s: &str) -> Result<Timestamp, E> {
+        s.parse().map_err(E::custom)
+    }
+}
+
+// Timestamps are read from csv as text, and from snapshots as numbers (see `Serialize`).
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+// Parse a duration in seconds, with an optional unit: `90d`, `12h`, `30m` or `45s`.
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => (&s[..index], unit),
+        _ => (s, 's'),
+    };
+    let factor = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => SECONDS_PER_DAY,
+        _ => return Err(format!("Unknown unit of duration '{}'.", s)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(factor))
+        .ok_or_else(|| format!("Invalid duration '{}'.", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp() {
+        let parse = |s: &str| s.parse::<Timestamp>().map(|timestamp| timestamp.0);
+        assert_eq!(parse("1643630400"), Ok(1643630400));
+        assert_eq!(parse("1970-01-01"), Ok(0));
+        assert_eq!(parse("2022-01-31T12:00:00Z"), Ok(1643630400));
+        // Leap day
+        assert_eq!(
+            parse("2024-03-01"),
+            Ok(parse("2024-02-28").unwrap() + 2 * 86400)
+        );
+        assert!(parse("2022-01-31T12:00:00").is_err());
+        assert!(parse("2022-13-01").is_err());
+        assert!(parse("22-01-01").is_err());
+        assert!(parse("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_date() {
+        let parse = |s: &str| s.parse::<Timestamp>();
+        assert!(parse("2022-02-31").is_err());
+        assert!(parse("2022-02-29").is_err());
+        assert!(parse("2022-04-31").is_err());
+        assert!(parse("2022-01-00").is_err());
+        assert!(parse("2022-01-32").is_err());
+        assert!(parse("2100-02-29").is_err());
+        assert!(parse("2024-02-29").is_ok());
+        assert!(parse("2000-02-29").is_ok());
+        assert!(parse("2022-12-31").is_ok());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("45"), Ok(45));
+        assert_eq!(parse_duration("45s"), Ok(45));
+        assert_eq!(parse_duration("30m"), Ok(1800));
+        assert_eq!(parse_duration("12h"), Ok(43200));
+        assert_eq!(parse_duration("90d"), Ok(90 * 86400));
+        assert!(parse_duration("90w").is_err());
+        assert!(parse_duration("d").is_err());
+    }
+}
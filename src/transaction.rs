@@ -0,0 +1,191 @@
+use crate::amount::Amount;
+use crate::client_state::{add, sub, Rejection};
+use crate::operation::{Operation, OperationType};
+use serde::{Deserialize, Serialize};
+
+// The state machine of a stored transaction:
+// Settled -> Disputed -> Resolved or ChargedBack. A resolved transaction can be disputed again,
+// a charged back transaction is final. A transaction stays disputed as long as a part of it is disputed, and ends
+// charged back if any part of it was charged back.
+// Authorizations have their own state machine: Authorized -> Captured or Voided. Both are final.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum TransactionStatus {
+    Settled,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    Authorized,
+    Captured,
+    Voided,
+}
+
+// A deposit, withdrawal or authorization stored to be referenced by later operations.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct Transaction {
+    pub operation: Operation,
+    pub status: TransactionStatus,
+    // The amount currently under dispute.
+    pub disputed: Amount,
+    // The amount that was charged back.
+    pub charged_back: Amount,
+    // The amount of a deposit that was refunded.
+    pub refunded: Amount,
+}
+
+impl Transaction {
+    pub fn new(operation: Operation) -> Transaction {
+        let status = match operation.type_ {
+            OperationType::Authorize => TransactionStatus::Authorized,
+            _ => TransactionStatus::Settled,
+        };
+        Transaction {
+            operation,
+            status,
+            disputed: Amount::ZERO,
+            charged_back: Amount::ZERO,
+            refunded: Amount::ZERO,
+        }
+    }
+
+    // Whether the transaction holds funds of the client: it is disputed or authorized.
+    pub fn is_held(&self) -> bool {
+        matches!(
+            self.status,
+            TransactionStatus::Disputed | TransactionStatus::Authorized
+        )
+    }
+
+    // Capture `amount` of the authorized amount (or all of it if `amount` is 0) and return the captured amount.
+    pub fn capture(&mut self, amount: Amount) -> Result<Amount, Rejection> {
+        if self.status != TransactionStatus::Authorized {
+            return Err(Rejection::NotAuthorized);
+        }
+
+        let amount = if amount == Amount::ZERO {
+            self.operation.amount
+        } else {
+            amount
+        };
+        if amount > self.operation.amount {
+            return Err(Rejection::ExceedsAuthorized);
+        }
+
+        self.status = TransactionStatus::Captured;
+        Ok(amount)
+    }
+
+    // Void the authorization and return the released amount.
+    pub fn void(&mut self) -> Result<Amount, Rejection> {
+        if self.status != TransactionStatus::Authorized {
+            return Err(Rejection::NotAuthorized);
+        }
+
+        self.status = TransactionStatus::Voided;
+        Ok(self.operation.amount)
+    }
+
+    // The amount that can still be disputed or refunded.
+    fn remainder(&self) -> Result<Amount, Rejection> {
+        sub(self.operation.amount, self.disputed)
+            .and_then(|remainder| sub(remainder, self.charged_back))
+            .and_then(|remainder| sub(remainder, self.refunded))
+    }
+
+    // The amount of a deposit a refund of `amount` returns (the entire remainder if `amount` is 0).
+    // Disputed parts can't be refunded, but the rest of a partially disputed deposit can.
+    pub fn refundable_amount(&self, amount: Amount) -> Result<Amount, Rejection> {
+        if self.operation.type_ != OperationType::Deposit {
+            return Err(Rejection::NotRefundable);
+        }
+        if self.status == TransactionStatus::ChargedBack {
+            return Err(Rejection::AlreadyChargedBack);
+        }
+
+        let remainder = self.remainder()?;
+        let amount = if amount == Amount::ZERO {
+            remainder
+        } else {
+            amount
+        };
+        if amount > remainder || remainder == Amount::ZERO {
+            return Err(Rejection::ExceedsRemainder);
+        }
+        Ok(amount)
+    }
+
+    // Dispute `amount` of the transaction (or the entire remainder if `amount` is 0) and return the disputed amount.
+    pub fn dispute(&mut self, amount: Amount) -> Result<Amount, Rejection> {
+        let remainder = self.remainder()?;
+        match self.status {
+            TransactionStatus::ChargedBack => return Err(Rejection::AlreadyChargedBack),
+            TransactionStatus::Disputed if remainder == Amount::ZERO => {
+                return Err(Rejection::AlreadyDisputed)
+            }
+            TransactionStatus::Authorized
+            | TransactionStatus::Captured
+            | TransactionStatus::Voided => return Err(Rejection::NotDisputable),
+            _ => {}
+        }
+
+        let amount = if amount == Amount::ZERO {
+            remainder
+        } else {
+            amount
+        };
+        // A fully refunded deposit has nothing left to dispute.
+        if amount > remainder || remainder == Amount::ZERO {
+            return Err(Rejection::ExceedsRemainder);
+        }
+
+        self.disputed = add(self.disputed, amount)?;
+        self.status = TransactionStatus::Disputed;
+        Ok(amount)
+    }
+
+    // Resolve `amount` of the disputed amount (or all of it if `amount` is 0) and return the resolved amount.
+    pub fn resolve(&mut self, amount: Amount) -> Result<Amount, Rejection> {
+        let amount = self.disputed_amount(amount)?;
+        self.disputed = sub(self.disputed, amount)?;
+        self.settle_dispute();
+        Ok(amount)
+    }
+
+    // Charge back `amount` of the disputed amount (or all of it if `amount` is 0) and return the charged back amount.
+    pub fn chargeback(&mut self, amount: Amount) -> Result<Amount, Rejection> {
+        let amount = self.disputed_amount(amount)?;
+        self.disputed = sub(self.disputed, amount)?;
+        self.charged_back = add(self.charged_back, amount)?;
+        self.settle_dispute();
+        Ok(amount)
+    }
+
+    // Once nothing is disputed anymore, the dispute ends. It ends charged back if any part was charged back.
+    fn settle_dispute(&mut self) {
+        if self.disputed > Amount::ZERO {
+            return;
+        }
+        self.status = if self.charged_back > Amount::ZERO {
+            TransactionStatus::ChargedBack
+        } else {
+            TransactionStatus::Resolved
+        };
+    }
+
+    fn disputed_amount(&self, amount: Amount) -> Result<Amount, Rejection> {
+        match self.status {
+            TransactionStatus::Disputed => {}
+            TransactionStatus::ChargedBack => return Err(Rejection::AlreadyChargedBack),
+            _ => return Err(Rejection::NotDisputed),
+        }
+
+        let amount = if amount == Amount::ZERO {
+            self.disputed
+        } else {
+            amount
+        };
+        if amount > self.disputed {
+            return Err(Rejection::ExceedsDisputed);
+        }
+        Ok(amount)
+    }
+}
@@ -0,0 +1,852 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::operation::{Operation, OperationType};
+use crate::timestamp::Timestamp;
+use crate::transaction::{Transaction, TransactionStatus};
+
+// Stores the transactions of a single client by their transaction id. Stores keeping the transactions outside of
+// memory report failures (e.g. a full disk) as errors.
+pub trait TransactionStore: Debug + Send {
+    fn get(&self, tx_id: u32) -> io::Result<Option<Transaction>>;
+
+    fn contains(&self, tx_id: u32) -> io::Result<bool> {
+        Ok(self.get(tx_id)?.is_some())
+    }
+
+    // Insert a new transaction or replace the stored one.
+    fn insert(&mut self, tx_id: u32, transaction: Transaction) -> io::Result<()>;
+
+    fn remove(&mut self, tx_id: u32) -> io::Result<()>;
+
+    fn len(&self) -> usize;
+
+    // All stored transactions, in no particular order. They are read one by one, so a store outside of memory
+    // doesn't have to load all of them at once.
+    fn transactions(&self) -> Box<dyn Iterator<Item = io::Result<Transaction>> + '_>;
+
+    // Whether the methods do blocking I/O. Such stores must not be used on the worker threads of the runtime,
+    // see `ClientState::offload`.
+    fn does_blocking_io(&self) -> bool {
+        false
+    }
+
+    fn clone_box(&self) -> Box<dyn TransactionStore>;
+}
+
+// A store of any kind.
+#[derive(Debug)]
+pub struct AnyStore(Box<dyn TransactionStore>);
+
+impl AnyStore {
+    pub fn new<S: TransactionStore + 'static>(store: S) -> AnyStore {
+        AnyStore(Box::new(store))
+    }
+}
+
+// Transactions are kept in memory by default.
+impl Default for AnyStore {
+    fn default() -> Self {
+        AnyStore::new(MemoryStore::default())
+    }
+}
+
+impl Deref for AnyStore {
+    type Target = dyn TransactionStore;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl DerefMut for AnyStore {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut()
+    }
+}
+
+impl Clone for AnyStore {
+    fn clone(&self) -> Self {
+        AnyStore(self.0.clone_box())
+    }
+}
+
+// Stores are equal if they contain the same transactions, no matter how they store them. Stores failing to read
+// their transactions are never equal.
+impl PartialEq for AnyStore {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self.transactions().all(|tx| {
+                tx.is_ok_and(
+                    |tx| matches!(other.get(tx.operation.tx_id), Ok(Some(other)) if other == tx),
+                )
+            })
+    }
+}
+
+// The default store, keeping all transactions in memory.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    transactions: HashMap<u32, Transaction>,
+}
+
+impl TransactionStore for MemoryStore {
+    fn get(&self, tx_id: u32) -> io::Result<Option<Transaction>> {
+        Ok(self.transactions.get(&tx_id).copied())
+    }
+
+    fn insert(&mut self, tx_id: u32, transaction: Transaction) -> io::Result<()> {
+        self.transactions.insert(tx_id, transaction);
+        Ok(())
+    }
+
+    fn remove(&mut self, tx_id: u32) -> io::Result<()> {
+        self.transactions.remove(&tx_id);
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = io::Result<Transaction>> + '_> {
+        Box::new(self.transactions.values().copied().map(Ok))
+    }
+
+    fn clone_box(&self) -> Box<dyn TransactionStore> {
+        Box::new(self.clone())
+    }
+}
+
+// The size of an encoded transaction, see `encode`.
+const RECORD_SIZE: usize = 93;
+// The number of records per segment file. Small in tests, so segments are rotated and compacted.
+const SEGMENT_RECORDS: u64 = if cfg!(test) { 4 } else { 1024 * 1024 };
+// The number of appended records buffered in memory before they are written. Small in tests, so records
+// are read from the files too.
+const BUFFERED_RECORDS: usize = if cfg!(test) { 2 } else { 256 };
+// The number of segment files kept open, see `OpenFiles`. Small in tests, so handles are closed and reopened.
+const OPEN_FILES: usize = if cfg!(test) { 8 } else { 256 };
+
+const OPERATION_TYPES: [OperationType; 12] = [
+    OperationType::Deposit,
+    OperationType::Withdrawal,
+    OperationType::Dispute,
+    OperationType::Resolve,
+    OperationType::Chargeback,
+    OperationType::Refund,
+    OperationType::Transfer,
+    OperationType::Authorize,
+    OperationType::Capture,
+    OperationType::Void,
+    OperationType::Unlock,
+    OperationType::Close,
+];
+
+const STATUSES: [TransactionStatus; 7] = [
+    TransactionStatus::Settled,
+    TransactionStatus::Disputed,
+    TransactionStatus::Resolved,
+    TransactionStatus::ChargedBack,
+    TransactionStatus::Authorized,
+    TransactionStatus::Captured,
+    TransactionStatus::Voided,
+];
+
+// Encode a transaction into a fixed size little endian record:
+// type (2), client (2), tx (4), amount (16), currency (8), to (1 + 2), timestamp (1 + 8), status (1), disputed (16),
+// charged back (16), refunded (16).
+fn encode(tx: &Transaction) -> [u8; RECORD_SIZE] {
+    let operation = &tx.operation;
+    let mut currency = [0; 8];
+    currency[..operation.currency.as_str().len()]
+        .copy_from_slice(operation.currency.as_str().as_bytes());
+    let status = STATUSES
+        .iter()
+        .position(|&status| status == tx.status)
+        .unwrap_or(0) as u8;
+
+    let mut record = [0; RECORD_SIZE];
+    let fields: [&[u8]; 13] = [
+        &(operation.type_ as u16).to_le_bytes(),
+        &operation.client.to_le_bytes(),
+        &operation.tx_id.to_le_bytes(),
+        &operation.amount.units().to_le_bytes(),
+        &currency,
+        &[operation.to.is_some() as u8],
+        &operation.to.unwrap_or(0).to_le_bytes(),
+        &[operation.timestamp.is_some() as u8],
+        &operation
+            .timestamp
+            .map_or(0, |time| time.seconds())
+            .to_le_bytes(),
+        &[status],
+        &tx.disputed.units().to_le_bytes(),
+        &tx.charged_back.units().to_le_bytes(),
+        &tx.refunded.units().to_le_bytes(),
+    ];
+    let mut offset = 0;
+    for field in fields {
+        record[offset..offset + field.len()].copy_from_slice(field);
+        offset += field.len();
+    }
+    record
+}
+
+// Reads the fields of a record one after another.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut field = [0; N];
+        field.copy_from_slice(&self.0[..N]);
+        self.0 = &self.0[N..];
+        field
+    }
+}
+
+fn decode(record: &[u8; RECORD_SIZE]) -> Option<Transaction> {
+    let mut fields = Fields(record);
+    let type_ = u16::from_le_bytes(fields.take());
+    let client = u16::from_le_bytes(fields.take());
+    let tx_id = u32::from_le_bytes(fields.take());
+    let amount = Amount::from_units(i128::from_le_bytes(fields.take()));
+    let currency: [u8; 8] = fields.take();
+    let [has_to] = fields.take();
+    let to = u16::from_le_bytes(fields.take());
+    let [has_timestamp] = fields.take();
+    let timestamp = u64::from_le_bytes(fields.take());
+    let [status] = fields.take();
+
+    let currency_length = currency.iter().position(|&byte| byte == 0).unwrap_or(8);
+    Some(Transaction {
+        operation: Operation {
+            type_: *OPERATION_TYPES
+                .iter()
+                .find(|&&operation_type| operation_type as u16 == type_)?,
+            client,
+            tx_id,
+            amount,
+            currency: std::str::from_utf8(&currency[..currency_length])
+                .ok()?
+                .parse::<Currency>()
+                .ok()?,
+            to: (has_to == 1).then_some(to),
+            timestamp: (has_timestamp == 1).then(|| Timestamp::from_seconds(timestamp)),
+        },
+        status: *STATUSES.get(status as usize)?,
+        disputed: Amount::from_units(i128::from_le_bytes(fields.take())),
+        charged_back: Amount::from_units(i128::from_le_bytes(fields.take())),
+        refunded: Amount::from_units(i128::from_le_bytes(fields.take())),
+    })
+}
+
+// A unique directory for the segment files of one run, created in the spill dir like a temporary directory.
+// Runs sharing a spill dir never touch each other's files. The directory is removed once the run is done.
+#[derive(Debug)]
+pub struct SpillDir {
+    parent: PathBuf,
+    directory: PathBuf,
+    // The number of records written to segment files so far.
+    written: AtomicU64,
+    // Numbers the logs, so copies of a client's log get files of their own.
+    next_log: AtomicU64,
+    files: Mutex<OpenFiles>,
+}
+
+// The open handles of the segment files, at most `OPEN_FILES`. Keeping a handle per segment would run out of file
+// descriptors with many clients, so the least recently used one is closed when another file is opened.
+#[derive(Debug, Default)]
+struct OpenFiles {
+    handles: HashMap<PathBuf, (Arc<File>, u64)>,
+    // Counts the uses of the handles, to find the least recently used one.
+    uses: u64,
+}
+
+impl SpillDir {
+    pub fn create(parent: &str) -> io::Result<SpillDir> {
+        fs::create_dir_all(parent)?;
+        // Another run may have picked the same random name, then another one is tried.
+        for _ in 0..16 {
+            let directory = Path::new(parent).join(format!("run-{:016x}", rand::random::<u64>()));
+            match fs::create_dir(&directory) {
+                Ok(()) => {
+                    return Ok(SpillDir {
+                        parent: PathBuf::from(parent),
+                        directory,
+                        written: AtomicU64::new(0),
+                        next_log: AtomicU64::new(0),
+                        files: Mutex::new(OpenFiles::default()),
+                    })
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Failed to create a unique directory in {}", parent),
+        ))
+    }
+
+    fn files(&self) -> MutexGuard<'_, OpenFiles> {
+        self.files.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // The handle of a segment file, opened (or created) if it is not open yet. A handle closed while it is still
+    // used by another client stays open until that client is done with it.
+    fn file(&self, path: &Path, create: bool) -> io::Result<Arc<File>> {
+        let mut files = self.files();
+        files.uses += 1;
+        let uses = files.uses;
+        if let Some((file, used)) = files.handles.get_mut(path) {
+            *used = uses;
+            return Ok(file.clone());
+        }
+
+        if files.handles.len() >= OPEN_FILES {
+            // Prefer closing a handle nobody else uses right now.
+            let least_recently_used = files
+                .handles
+                .iter()
+                .min_by_key(|(_, (file, used))| (Arc::strong_count(file) > 1, *used))
+                .map(|(path, _)| path.clone());
+            if let Some(path) = least_recently_used {
+                files.handles.remove(&path);
+            }
+        }
+        let file = Arc::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(create)
+                .open(path)?,
+        );
+        files
+            .handles
+            .insert(path.to_path_buf(), (file.clone(), uses));
+        Ok(file)
+    }
+
+    // The number of records written to segment files so far, to check in tests that the disk was actually used.
+    #[cfg(test)]
+    pub fn records_written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.files().handles.remove(path);
+        fs::remove_file(path)
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        self.files().handles.clear();
+        if let Err(err) = fs::remove_dir_all(&self.directory) {
+            eprintln!(
+                "Failed to remove the segment files in {} with {}",
+                self.parent.display(),
+                err
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    id: u64,
+    // The number of records in the segment, including the buffered ones of the last segment.
+    records: u64,
+}
+
+// The segment files of a single client. Records are only appended: updating a transaction appends a new record
+// and moves the index to it. Superseded records are dropped when the segments are compacted, see `rotate`.
+#[derive(Debug)]
+struct ClientLog {
+    spill_dir: Arc<SpillDir>,
+    client: u16,
+    log: u64,
+    // Oldest first, records are appended to the last segment.
+    segments: Vec<Segment>,
+    next_segment: u64,
+    // The records appended to the last segment that are not written yet.
+    buffer: Vec<u8>,
+    // The segment id and record number of each stored transaction.
+    index: HashMap<u32, (u64, u64)>,
+    // Set once a write failed. Which records reached the file is unknown then, so all later reads and writes
+    // fail too.
+    failed: bool,
+}
+
+impl ClientLog {
+    fn new(spill_dir: Arc<SpillDir>, client: u16) -> ClientLog {
+        let log = spill_dir.next_log.fetch_add(1, Ordering::Relaxed);
+        ClientLog {
+            spill_dir,
+            client,
+            log,
+            segments: Vec::new(),
+            next_segment: 0,
+            buffer: Vec::new(),
+            index: HashMap::new(),
+            failed: false,
+        }
+    }
+
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.spill_dir
+            .directory
+            .join(format!("client-{}-{}-{:05}.bin", self.client, self.log, id))
+    }
+
+    fn check_failed(&self) -> io::Result<()> {
+        if self.failed {
+            return Err(io::Error::other(
+                "An earlier write to the segment files failed",
+            ));
+        }
+        Ok(())
+    }
+
+    fn open_segment(&mut self) -> io::Result<()> {
+        let id = self.next_segment;
+        self.next_segment += 1;
+        self.spill_dir.file(&self.segment_path(id), true)?;
+        self.segments.push(Segment { id, records: 0 });
+        Ok(())
+    }
+
+    fn write_buffer(&mut self) -> io::Result<()> {
+        let segment = match self.segments.last() {
+            Some(segment) if !self.buffer.is_empty() => segment,
+            _ => return Ok(()),
+        };
+        let buffered = (self.buffer.len() / RECORD_SIZE) as u64;
+        let written = segment.records - buffered;
+        let mut file = &*self.spill_dir.file(&self.segment_path(segment.id), false)?;
+        file.seek(SeekFrom::Start(written * RECORD_SIZE as u64))?;
+        file.write_all(&self.buffer)?;
+        self.spill_dir
+            .written
+            .fetch_add(buffered, Ordering::Relaxed);
+        self.buffer.clear();
+        Ok(())
+    }
+
+    // Add the record to the last segment and return its position.
+    fn push(&mut self, record: &[u8; RECORD_SIZE]) -> io::Result<(u64, u64)> {
+        let segment = self
+            .segments
+            .last_mut()
+            .ok_or_else(|| io::Error::other("No segment to append to"))?;
+        let position = (segment.id, segment.records);
+        segment.records += 1;
+        self.buffer.extend_from_slice(record);
+        if self.buffer.len() >= BUFFERED_RECORDS * RECORD_SIZE {
+            self.write_buffer()?;
+        }
+        Ok(position)
+    }
+
+    // Append the record to the last segment, starting a new one if it is full, and return its position.
+    fn append(&mut self, record: &[u8; RECORD_SIZE]) -> io::Result<(u64, u64)> {
+        if self
+            .segments
+            .last()
+            .is_none_or(|segment| segment.records >= SEGMENT_RECORDS)
+        {
+            self.rotate()?;
+        }
+        self.push(record)
+    }
+
+    // Start a new segment. If at least half of the records are superseded (by updates or removed transactions),
+    // the live records are copied into the new segment and the old segments are deleted. So the disk use grows
+    // with the number of stored transactions instead of the number of updates.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.write_buffer()?;
+        let records: u64 = self.segments.iter().map(|segment| segment.records).sum();
+        let live = self.index.len() as u64;
+        let old_segments = self.segments.len();
+        self.open_segment()?;
+        if records == 0 || records < 2 * live {
+            return Ok(());
+        }
+
+        // The records are copied one by one, so only the buffer is kept in memory.
+        let mut index = std::mem::take(&mut self.index);
+        let copied = self.move_records(&mut index);
+        self.index = index;
+        copied?;
+
+        for segment in self.segments.drain(..old_segments).collect::<Vec<_>>() {
+            self.spill_dir.remove_file(&self.segment_path(segment.id))?;
+        }
+        Ok(())
+    }
+
+    // Copy the records at the positions in `index` to the last segment and move the positions there.
+    fn move_records(&mut self, index: &mut HashMap<u32, (u64, u64)>) -> io::Result<()> {
+        for position in index.values_mut() {
+            let record = self.read(*position)?;
+            *position = self.push(&record)?;
+        }
+        self.write_buffer()
+    }
+
+    fn read(&self, (id, record_number): (u64, u64)) -> io::Result<[u8; RECORD_SIZE]> {
+        self.check_failed()?;
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| segment.id == id)
+            .ok_or_else(|| io::Error::other(format!("Missing segment {}", id)))?;
+        let mut record = [0; RECORD_SIZE];
+
+        // The latest records of the last segment may still be buffered.
+        let written = segment.records - (self.buffer.len() / RECORD_SIZE) as u64;
+        let last = self.segments.last().map(|segment| segment.id);
+        if last == Some(id) && record_number >= written {
+            let offset = (record_number - written) as usize * RECORD_SIZE;
+            record.copy_from_slice(&self.buffer[offset..offset + RECORD_SIZE]);
+            return Ok(record);
+        }
+
+        let mut file = &*self.spill_dir.file(&self.segment_path(id), false)?;
+        file.seek(SeekFrom::Start(record_number * RECORD_SIZE as u64))?;
+        file.read_exact(&mut record)?;
+        Ok(record)
+    }
+
+    fn insert(&mut self, tx_id: u32, record: &[u8; RECORD_SIZE]) -> io::Result<()> {
+        self.check_failed()?;
+        match self.append(record) {
+            Ok(position) => {
+                self.index.insert(tx_id, position);
+                Ok(())
+            }
+            Err(err) => {
+                self.failed = true;
+                Err(err)
+            }
+        }
+    }
+
+    // A copy with segment files of its own, holding only the live records.
+    fn copy(&self) -> io::Result<ClientLog> {
+        self.check_failed()?;
+        let mut copy = ClientLog::new(self.spill_dir.clone(), self.client);
+        copy.open_segment()?;
+        for (&tx_id, &position) in self.index.iter() {
+            let position = copy.push(&self.read(position)?)?;
+            copy.index.insert(tx_id, position);
+        }
+        copy.write_buffer()?;
+        Ok(copy)
+    }
+}
+
+// The segment files of a client are removed with its log, e.g. when a copy is dropped.
+impl Drop for ClientLog {
+    fn drop(&mut self) {
+        for segment in self.segments.iter() {
+            let path = self.segment_path(segment.id);
+            if let Err(err) = self.spill_dir.remove_file(&path) {
+                eprintln!("Failed to remove {} with {}", path.display(), err);
+            }
+        }
+    }
+}
+
+// Keeps the transactions of a client on disk, in segment files of its own. Only the index from transaction id
+// to record position is kept in memory.
+#[derive(Debug)]
+pub struct DiskStore {
+    log: ClientLog,
+}
+
+impl DiskStore {
+    pub fn new(spill_dir: Arc<SpillDir>, client: u16) -> DiskStore {
+        DiskStore {
+            log: ClientLog::new(spill_dir, client),
+        }
+    }
+}
+
+// A clone copies the live records into new segment files, so changing it leaves the original untouched, like
+// a clone of a `MemoryStore`. If the copy fails, all operations on the clone fail.
+impl Clone for DiskStore {
+    fn clone(&self) -> Self {
+        let log = self.log.copy().unwrap_or_else(|err| {
+            eprintln!("Failed to copy the segment files with {}", err);
+            let mut log = ClientLog::new(self.log.spill_dir.clone(), self.log.client);
+            log.failed = true;
+            log
+        });
+        DiskStore { log }
+    }
+}
+
+impl TransactionStore for DiskStore {
+    fn get(&self, tx_id: u32) -> io::Result<Option<Transaction>> {
+        self.log.check_failed()?;
+        let position = match self.log.index.get(&tx_id) {
+            Some(&position) => position,
+            None => return Ok(None),
+        };
+        // A record that can't be decoded is corrupt, the transaction is not unknown.
+        decode(&self.log.read(position)?).map(Some).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Corrupt record of transaction {}", tx_id),
+            )
+        })
+    }
+
+    // Only looks up the index, no record is read.
+    fn contains(&self, tx_id: u32) -> io::Result<bool> {
+        self.log.check_failed()?;
+        Ok(self.log.index.contains_key(&tx_id))
+    }
+
+    fn insert(&mut self, tx_id: u32, transaction: Transaction) -> io::Result<()> {
+        self.log.insert(tx_id, &encode(&transaction))
+    }
+
+    fn remove(&mut self, tx_id: u32) -> io::Result<()> {
+        self.log.index.remove(&tx_id);
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.log.index.len()
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = io::Result<Transaction>> + '_> {
+        Box::new(
+            self.log
+                .index
+                .keys()
+                .filter_map(|&tx_id| self.get(tx_id).transpose()),
+        )
+    }
+
+    fn does_blocking_io(&self) -> bool {
+        true
+    }
+
+    fn clone_box(&self) -> Box<dyn TransactionStore> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_round_trip() {
+        let mut tx = Transaction::new(
+            Operation::transfer(1, 2, 12345, 3)
+                .with_currency("USDT")
+                .with_timestamp("2022-01-31"),
+        );
+        tx.status = TransactionStatus::Disputed;
+        tx.disputed = Amount::from_units(100);
+        tx.charged_back = Amount::from_units(5);
+        tx.refunded = Amount::from_units(7);
+        assert_eq!(decode(&encode(&tx)), Some(tx));
+
+        let tx = Transaction::new(Operation::authorize(65535, u32::MAX, i128::MAX));
+        assert_eq!(decode(&encode(&tx)), Some(tx));
+    }
+
+    #[test]
+    fn test_disk_store() {
+        let parent = std::env::temp_dir().join("payment-engine-test-disk-store");
+        let spill_dir = Arc::new(SpillDir::create(parent.to_str().unwrap()).unwrap());
+        let mut store = DiskStore::new(spill_dir.clone(), 0);
+        let mut other = DiskStore::new(spill_dir.clone(), 1);
+        assert!(store.does_blocking_io());
+
+        let mut tx = Transaction::new(Operation::deposit(0, 1, 10));
+        store.insert(1, tx).unwrap();
+        other
+            .insert(1, Transaction::new(Operation::deposit(1, 1, 20)))
+            .unwrap();
+        store
+            .insert(2, Transaction::new(Operation::deposit(0, 2, 30)))
+            .unwrap();
+        assert_eq!(store.get(1).unwrap(), Some(tx));
+        assert_eq!(
+            other.get(1).unwrap().unwrap().operation.amount,
+            Amount::from_units(20)
+        );
+
+        // Updates append a new record.
+        tx.dispute(Amount::ZERO).unwrap();
+        store.insert(1, tx).unwrap();
+        assert_eq!(store.get(1).unwrap(), Some(tx));
+        assert_eq!(store.len(), 2);
+
+        store.remove(2).unwrap();
+        assert_eq!(store.get(2).unwrap(), None);
+        assert_eq!(
+            store
+                .transactions()
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap(),
+            vec![tx]
+        );
+
+        let mut memory = MemoryStore::default();
+        memory.insert(1, tx).unwrap();
+        assert_eq!(AnyStore::new(memory), AnyStore::new(store));
+        assert!(spill_dir.written.load(Ordering::Relaxed) > 0);
+    }
+
+    // Updating the same transactions over and over doesn't grow the segment files.
+    #[test]
+    fn test_segments_are_compacted() {
+        let parent = std::env::temp_dir().join("payment-engine-test-compaction");
+        let spill_dir = Arc::new(SpillDir::create(parent.to_str().unwrap()).unwrap());
+        let mut store = DiskStore::new(spill_dir.clone(), 0);
+        let mut transactions = [
+            Transaction::new(Operation::deposit(0, 1, 10)),
+            Transaction::new(Operation::deposit(0, 2, 20)),
+        ];
+        for round in 0..100 {
+            for tx in transactions.iter_mut() {
+                tx.refunded = Amount::from_units(round);
+                store.insert(tx.operation.tx_id, *tx).unwrap();
+            }
+        }
+        for tx in transactions {
+            assert_eq!(store.get(tx.operation.tx_id).unwrap(), Some(tx));
+        }
+
+        let records: u64 = store
+            .log
+            .segments
+            .iter()
+            .map(|segment| segment.records)
+            .sum();
+        assert!(records <= 2 * SEGMENT_RECORDS, "{} records", records);
+        let files = fs::read_dir(&spill_dir.directory).unwrap().count();
+        assert_eq!(files, store.log.segments.len());
+    }
+
+    // Runs sharing a spill dir write into directories of their own, which are removed afterwards.
+    #[test]
+    fn test_spill_dirs_are_unique() {
+        let parent = std::env::temp_dir().join("payment-engine-test-unique-spill-dir");
+        let parent = parent.to_str().unwrap();
+        let first = SpillDir::create(parent).unwrap();
+        let second = SpillDir::create(parent).unwrap();
+        assert_ne!(first.directory, second.directory);
+
+        let directory = first.directory.clone();
+        let mut store = DiskStore::new(Arc::new(first), 0);
+        let tx = Transaction::new(Operation::deposit(0, 1, 10));
+        store.insert(1, tx).unwrap();
+        assert_eq!(store.get(1).unwrap(), Some(tx));
+        drop(store);
+        assert!(!directory.exists());
+    }
+
+    #[test]
+    fn test_corrupt_records_are_errors() {
+        let parent = std::env::temp_dir().join("payment-engine-test-corrupt-record");
+        let spill_dir = Arc::new(SpillDir::create(parent.to_str().unwrap()).unwrap());
+        let mut store = DiskStore::new(spill_dir, 0);
+        store
+            .insert(1, Transaction::new(Operation::deposit(0, 1, 10)))
+            .unwrap();
+        // An unknown operation type
+        store.log.buffer[0] = 0xff;
+        assert_eq!(store.get(1).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    // A clone is a copy: changing it leaves the original untouched, and its files are removed with it.
+    #[test]
+    fn test_clones_are_copies() {
+        let parent = std::env::temp_dir().join("payment-engine-test-disk-store-clone");
+        let spill_dir = Arc::new(SpillDir::create(parent.to_str().unwrap()).unwrap());
+        let mut store = DiskStore::new(spill_dir.clone(), 0);
+        let tx = Transaction::new(Operation::deposit(0, 1, 10));
+        for tx_id in 1..=5 {
+            store.insert(tx_id, tx).unwrap();
+        }
+
+        let mut copy = store.clone();
+        let mut disputed = tx;
+        disputed.dispute(Amount::ZERO).unwrap();
+        copy.insert(1, disputed).unwrap();
+        copy.remove(2).unwrap();
+        assert_eq!(store.get(1).unwrap(), Some(tx));
+        assert_eq!(store.get(2).unwrap(), Some(tx));
+        assert_eq!(copy.get(1).unwrap(), Some(disputed));
+        assert_eq!(copy.get(3).unwrap(), Some(tx));
+
+        let files = || fs::read_dir(&spill_dir.directory).unwrap().count();
+        let with_copy = files();
+        drop(copy);
+        assert!(files() < with_copy);
+        assert_eq!(store.len(), 5);
+    }
+
+    // More clients than file descriptors: handles are closed and reopened instead of running out of them.
+    // The test runs itself again with a lower limit of open files.
+    #[cfg(unix)]
+    #[test]
+    fn test_more_clients_than_open_files() {
+        const LIMIT: usize = 64;
+        if std::env::var_os("PAYMENT_ENGINE_TEST_FILE_LIMIT").is_none() {
+            let status = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!(
+                    "ulimit -n {} && exec \"$0\" --exact --test-threads 1 $1",
+                    LIMIT
+                ))
+                .arg(std::env::current_exe().unwrap())
+                .arg("transaction_store::tests::test_more_clients_than_open_files")
+                .env("PAYMENT_ENGINE_TEST_FILE_LIMIT", "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let parent = std::env::temp_dir().join("payment-engine-test-open-files");
+        let spill_dir = Arc::new(SpillDir::create(parent.to_str().unwrap()).unwrap());
+        let clients = LIMIT as u16 * 4;
+        let mut stores: Vec<DiskStore> = (0..clients)
+            .map(|client| DiskStore::new(spill_dir.clone(), client))
+            .collect();
+        for tx_id in 0..3 {
+            for (client, store) in stores.iter_mut().enumerate() {
+                let tx = Transaction::new(Operation::deposit(client as u16, tx_id, 10));
+                store.insert(tx_id, tx).unwrap();
+            }
+        }
+        for (client, store) in stores.iter().enumerate() {
+            for tx_id in 0..3 {
+                let tx = store.get(tx_id).unwrap().unwrap();
+                assert_eq!(tx.operation.client, client as u16);
+            }
+        }
+        assert!(spill_dir.files().handles.len() <= OPEN_FILES);
+        let files = fs::read_dir(&spill_dir.directory).unwrap().count();
+        assert!(files > LIMIT, "{} files", files);
+    }
+}
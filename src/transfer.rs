@@ -0,0 +1,91 @@
+use tokio::sync::oneshot;
+
+use crate::client_state::{ClientState, Rejection, Transition};
+use crate::operation::InputOperation;
+
+// A transfer touches two clients that are computed in different futures. To apply it atomically, both futures
+// exchange messages when they reach the transfer:
+// 1. The receiver tells the sender whether it can receive the funds (e.g. it is not locked).
+// 2. The sender debits its funds if the receiver is ready and tells the receiver whether it did.
+// 3. The receiver credits the funds if the sender was debited.
+// Each future only waits for the other future to reach the same operation. All operations before it only wait for
+// earlier operations, so the futures can't wait for each other in a cycle.
+pub fn split_transfer(input: InputOperation) -> (TransferOut, TransferIn) {
+    let (ready_sender, ready_receiver) = oneshot::channel();
+    let (debited_sender, debited_receiver) = oneshot::channel();
+
+    (
+        TransferOut {
+            input,
+            receiver_ready: ready_receiver,
+            debited: debited_sender,
+        },
+        TransferIn {
+            input,
+            receiver_ready: ready_sender,
+            debited: debited_receiver,
+        },
+    )
+}
+
+// The half of a transfer applied to the sending client.
+#[derive(Debug)]
+pub struct TransferOut {
+    pub input: InputOperation,
+    receiver_ready: oneshot::Receiver<bool>,
+    debited: oneshot::Sender<bool>,
+}
+
+impl TransferOut {
+    pub async fn apply(
+        self,
+        client_state: ClientState,
+    ) -> (ClientState, Result<Transition, Rejection>) {
+        // If the receiving future is gone, it can't be credited either.
+        let (client_state, result) = match self.receiver_ready.await {
+            Ok(true) => {
+                let operation = self.input.operation;
+                client_state
+                    .offload(move |state| state.apply_operation(operation))
+                    .await
+            }
+            _ => (client_state, Err(Rejection::CounterpartyRejected)),
+        };
+        // The receiving future may be gone if it failed - there is nothing left to do then.
+        let _ = self.debited.send(result.is_ok());
+        (client_state, result)
+    }
+}
+
+// The half of a transfer applied to the receiving client.
+#[derive(Debug)]
+pub struct TransferIn {
+    pub input: InputOperation,
+    receiver_ready: oneshot::Sender<bool>,
+    debited: oneshot::Receiver<bool>,
+}
+
+impl TransferIn {
+    pub async fn apply(
+        self,
+        client_state: ClientState,
+    ) -> (ClientState, Result<Transition, Rejection>) {
+        let ready = client_state.can_receive_transfer(&self.input.operation);
+        if self.receiver_ready.send(ready.is_ok()).is_err() || ready.is_err() {
+            return (
+                client_state,
+                ready.and(Err(Rejection::CounterpartyRejected)),
+            );
+        }
+
+        match self.debited.await {
+            Ok(true) => {
+                let operation = self.input.operation;
+                client_state
+                    .offload(move |state| state.apply_operation(operation))
+                    .await
+            }
+            _ => (client_state, Err(Rejection::CounterpartyRejected)),
+        }
+    }
+}